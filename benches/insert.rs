@@ -0,0 +1,56 @@
+//! Timing of the hot paths on a large synthetic feed, run with
+//! `cargo bench`. Uses a fixed seed so runs are comparable.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+use cidrmerge::{Cidr, CidrSet};
+
+/// Small deterministic generator so the bench needs no dependencies.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 11
+    }
+}
+
+fn feed(n: usize) -> Vec<Cidr> {
+    let mut rng = Lcg(42);
+    (0..n)
+        .map(|i| match i % 10 {
+            // Mostly scattered hosts, some IPv4 networks and IPv6 /64s
+            0 => Cidr::new(Ipv4Addr::from(rng.next() as u32), 24).unwrap(),
+            1 => Cidr::new(Ipv6Addr::from(u128::from(rng.next()) << 64), 64).unwrap(),
+            _ => Cidr::from(Ipv4Addr::from(rng.next() as u32)),
+        })
+        .collect()
+}
+
+fn time<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    println!(
+        "{:<24} {:>10.1} ms",
+        name,
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+    result
+}
+
+fn main() {
+    let cidrs = time("generate 1M", || feed(1_000_000));
+    let mut set = time("insert 1M", || {
+        let mut set = CidrSet::new();
+        cidrs.iter().for_each(|c| set.insert(c));
+        set
+    });
+    println!("{:<24} {:>10}", "nodes", set.nodes());
+    println!("{:<24} {:>10}", "cidrs", set.len());
+    time("iterate", || set.iter().count());
+    time("approximate to 1000", || set.approximate(1000));
+}
@@ -0,0 +1,626 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// The address family of a `Cidr`. IPv4 sorts before IPv6.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    /// Guess the family of an address from its notation.
+    pub(crate) fn of(ip: &str) -> Self {
+        if ip.contains(':') {
+            Family::V6
+        } else {
+            Family::V4
+        }
+    }
+    /// Number of bits in an address.
+    pub fn width(self) -> usize {
+        match self {
+            Family::V4 => 32,
+            Family::V6 => 128,
+        }
+    }
+}
+
+/// Why a CIDR or range failed to parse.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    InvalidPrefixLength(String),
+    PrefixLengthTooLong {
+        size: usize,
+        max: usize,
+    },
+    InvalidOctet(String),
+    WrongOctetCount(usize),
+    InvalidIpv6(String),
+    InvalidRange(String),
+    MixedFamilies(String),
+    NonContiguousMask(String),
+    /// `0.0.0.0 255.255.255.255`, either the host 0.0.0.0 or everything.
+    AmbiguousMask(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::InvalidPrefixLength(s) => write!(f, "invalid prefix length {:?}", s),
+            ParseError::PrefixLengthTooLong { size, max } => {
+                write!(f, "prefix length {} is longer than {}", size, max)
+            }
+            ParseError::InvalidOctet(s) => write!(f, "invalid octet {:?}", s),
+            ParseError::WrongOctetCount(n) => write!(f, "expected 4 octets, found {}", n),
+            ParseError::InvalidIpv6(s) => write!(f, "invalid IPv6 address {:?}", s),
+            ParseError::InvalidRange(s) => write!(f, "invalid address range {:?}", s),
+            ParseError::MixedFamilies(s) => write!(f, "range mixes IPv4 and IPv6: {:?}", s),
+            ParseError::NonContiguousMask(s) => write!(f, "non-contiguous mask {:?}", s),
+            ParseError::AmbiguousMask(s) => {
+                write!(f, "ambiguous mask {:?} on 0.0.0.0, use a prefix length", s)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An IPv4 or IPv6 prefix such as `10.0.0.0/8` or `2001:db8::/32`.
+///
+/// CIDRs order by family, then address, then prefix length, so a CIDR sorts
+/// right before the CIDRs inside it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cidr {
+    // Field order gives the derived ordering
+    pub(crate) family: Family,
+    /// The first address, right-aligned so IPv4 uses the low 32 bits. Host
+    /// bits beyond the prefix are always zero.
+    pub(crate) value: u128,
+    pub(crate) size: u8,
+}
+
+impl Cidr {
+    /// The CIDR of the given prefix length containing `address`. Host bits
+    /// beyond the prefix are cleared.
+    pub fn new<A: Into<IpAddr>>(address: A, size: usize) -> Result<Self, ParseError> {
+        let (family, value) = match address.into() {
+            IpAddr::V4(a) => (Family::V4, u128::from(u32::from(a))),
+            IpAddr::V6(a) => (Family::V6, u128::from(a)),
+        };
+        if size > family.width() {
+            return Err(ParseError::PrefixLengthTooLong {
+                size,
+                max: family.width(),
+            });
+        }
+        Ok(Self::from_value(family, value, size))
+    }
+    /// The first address in this CIDR as a `std::net` address.
+    pub fn address(&self) -> IpAddr {
+        match self.family {
+            Family::V4 => IpAddr::V4(Ipv4Addr::from(self.first() as u32)),
+            Family::V6 => IpAddr::V6(Ipv6Addr::from(self.first())),
+        }
+    }
+    pub(crate) fn parse_address(family: Family, ip: &str) -> Result<u128, ParseError> {
+        match family {
+            Family::V4 => {
+                let groups = ip
+                    .split('.')
+                    .map(|g| {
+                        // Only plain decimal, as other tools read a leading
+                        // zero as octal
+                        let decimal = g.bytes().all(|b| b.is_ascii_digit())
+                            && (g == "0" || !g.starts_with('0'));
+                        g.parse()
+                            .ok()
+                            .filter(|_| decimal)
+                            .ok_or_else(|| ParseError::InvalidOctet(g.to_string()))
+                    })
+                    .collect::<Result<Vec<u8>, _>>()?;
+                if groups.len() != 4 {
+                    return Err(ParseError::WrongOctetCount(groups.len()));
+                }
+                Ok(groups.iter().fold(0, |acc, g| acc << 8 | u128::from(*g)))
+            }
+            Family::V6 => ip
+                .parse::<Ipv6Addr>()
+                .map(u128::from)
+                .map_err(|_| ParseError::InvalidIpv6(ip.to_string())),
+        }
+    }
+    pub(crate) fn format_address(family: Family, value: u128) -> String {
+        // Ipv6Addr's Display already produces the RFC 5952 canonical form
+        match family {
+            Family::V4 => Ipv4Addr::from(value as u32).to_string(),
+            Family::V6 => Ipv6Addr::from(value).to_string(),
+        }
+    }
+    /// The CIDR of the given size that contains the address `value`.
+    pub(crate) fn from_value(family: Family, value: u128, size: usize) -> Self {
+        Cidr {
+            family,
+            value: value & !host_mask(family.width() - size),
+            size: size as u8,
+        }
+    }
+    /// The first address in this CIDR. IPv4 addresses use the low 32 bits.
+    pub fn first(&self) -> u128 {
+        self.value
+    }
+    /// The last address in this CIDR.
+    pub fn last(&self) -> u128 {
+        self.value | self.host_mask()
+    }
+    /// Whether `other` is equal to or inside this CIDR.
+    pub fn contains(&self, other: &Cidr) -> bool {
+        self.family == other.family
+            && self.size() <= other.size()
+            && other.value & !self.host_mask() == self.value
+    }
+    /// Bit `i` of the address, counting from the most significant.
+    pub(crate) fn bit(&self, i: usize) -> bool {
+        self.value >> (self.width() - 1 - i) & 1 == 1
+    }
+    fn host_mask(&self) -> u128 {
+        host_mask(self.width() - self.size())
+    }
+    /// The prefix length.
+    pub fn size(&self) -> usize {
+        usize::from(self.size)
+    }
+    pub(crate) fn width(&self) -> usize {
+        self.family.width()
+    }
+    /// Number of addresses covered by this CIDR, as a float so that /0 in
+    /// IPv6 does not overflow.
+    pub fn addresses(&self) -> f64 {
+        2.0_f64.powi((self.width() - self.size()) as i32)
+    }
+    /// Exact number of addresses covered by this CIDR, except that `::/0`
+    /// saturates at `u128::MAX`.
+    pub fn count(&self) -> u128 {
+        host_mask(self.width() - self.size()).saturating_add(1)
+    }
+    pub(crate) fn root(family: Family) -> Self {
+        Cidr {
+            family,
+            value: 0,
+            size: 0,
+        }
+    }
+    /// Convert an IPv4 netmask (`255.255.255.0`) or wildcard mask
+    /// (`0.0.0.255`) to a prefix length. Masks starting with a one bit are
+    /// netmasks, the rest are wildcard masks, except that `0.0.0.0` is the
+    /// netmask of the default route.
+    fn parse_mask(mask: &str) -> Result<usize, ParseError> {
+        let value = Self::parse_address(Family::V4, mask)? as u32;
+        let netmask = if value.leading_ones() > 0 || value == 0 {
+            value
+        } else {
+            !value
+        };
+        if netmask.leading_ones() + netmask.trailing_zeros() != 32 {
+            return Err(ParseError::NonContiguousMask(mask.to_string()));
+        }
+        Ok(netmask.leading_ones() as usize)
+    }
+    /// Parse a prefix in CIDR, netmask or wildcard mask notation. A bare
+    /// address is parsed as a single host.
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        // The prefix length or mask follows either a slash or whitespace
+        let (ip, size) = match s.split_once(|c: char| c == '/' || c.is_whitespace()) {
+            Some((ip, size)) => (ip, Some(size.trim_start())),
+            None => (s, None),
+        };
+        let family = Family::of(ip);
+        let mask = family == Family::V4 && size.is_some_and(|m| m.contains('.'));
+        // A bare address is a single host
+        let size: usize = match size {
+            Some(mask) if family == Family::V4 && mask.contains('.') => Self::parse_mask(mask)?,
+            Some(size) => size
+                .parse()
+                .map_err(|_| ParseError::InvalidPrefixLength(size.to_string()))?,
+            None => family.width(),
+        };
+        if size > family.width() {
+            return Err(ParseError::PrefixLengthTooLong {
+                size,
+                max: family.width(),
+            });
+        }
+        let value = Self::parse_address(family, ip)?;
+        // The host 0.0.0.0 as a netmask, but everything as a Cisco wildcard
+        if mask && value == 0 && size == 32 {
+            return Err(ParseError::AmbiguousMask("255.255.255.255".to_string()));
+        }
+        Ok(Self::from_value(family, value, size))
+    }
+    /// The CIDR one bit shorter that contains this one.
+    pub(crate) fn parent(&self) -> Self {
+        Self::from_value(self.family, self.value, self.size() - 1)
+    }
+    /// The two CIDRs one bit longer that make up this one, lower half first.
+    pub(crate) fn halves(&self) -> [Self; 2] {
+        let size = self.size() + 1;
+        let high = 1 << (self.width() - size);
+        [
+            Self::from_value(self.family, self.value, size),
+            Self::from_value(self.family, self.value | high, size),
+        ]
+    }
+    /// The longest CIDR containing both `self` and `other`, which must be of
+    /// the same family.
+    pub(crate) fn common(&self, other: &Cidr) -> Self {
+        let equal = (self.value ^ other.value).leading_zeros() as usize - (128 - self.width());
+        let size = equal.min(self.size()).min(other.size());
+        Self::from_value(self.family, self.value, size)
+    }
+    pub fn family(&self) -> Family {
+        self.family
+    }
+    /// Format as `address/length`, IPv6 in RFC 5952 canonical form.
+    pub fn to_pretty_string(&self) -> String {
+        format!(
+            "{}/{}",
+            Self::format_address(self.family, self.first()),
+            self.size()
+        )
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&self.to_pretty_string())
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        Cidr::parse(s)
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    /// A single host CIDR.
+    fn from(address: IpAddr) -> Self {
+        let size = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Cidr::new(address, size).unwrap()
+    }
+}
+
+impl From<Ipv4Addr> for Cidr {
+    fn from(address: Ipv4Addr) -> Self {
+        Cidr::from(IpAddr::V4(address))
+    }
+}
+
+impl From<Ipv6Addr> for Cidr {
+    fn from(address: Ipv6Addr) -> Self {
+        Cidr::from(IpAddr::V6(address))
+    }
+}
+
+impl From<Cidr> for (IpAddr, usize) {
+    fn from(cidr: Cidr) -> Self {
+        (cidr.address(), cidr.size())
+    }
+}
+
+/// A mask with the lowest `bits` bits set.
+pub(crate) fn host_mask(bits: usize) -> u128 {
+    if bits >= 128 {
+        u128::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// An inclusive range of addresses such as `10.0.0.3-10.0.0.17`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub family: Family,
+    pub first: u128,
+    pub last: u128,
+}
+
+impl Range {
+    /// Parse `first-last`, both addresses in the same family.
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        let (first, last) = s
+            .split_once('-')
+            .ok_or_else(|| ParseError::InvalidRange(s.to_string()))?;
+        let (first, last) = (first.trim(), last.trim());
+        let family = Family::of(first);
+        if Family::of(last) != family {
+            return Err(ParseError::MixedFamilies(s.to_string()));
+        }
+        let range = Range {
+            family,
+            first: Cidr::parse_address(family, first)?,
+            last: Cidr::parse_address(family, last)?,
+        };
+        if range.first > range.last {
+            return Err(ParseError::InvalidRange(s.to_string()));
+        }
+        Ok(range)
+    }
+    /// Split the range into the minimal list of aligned CIDRs covering it.
+    pub fn cidrs(&self) -> Vec<Cidr> {
+        let width = self.family.width();
+        let mut cidrs = vec![];
+        let mut start = self.first;
+        loop {
+            // Largest block aligned at `start` that does not pass `last`
+            let mut host_bits = (start.trailing_zeros() as usize).min(width);
+            while start | host_mask(host_bits) > self.last {
+                host_bits -= 1;
+            }
+            cidrs.push(Cidr::from_value(self.family, start, width - host_bits));
+
+            let end = start | host_mask(host_bits);
+            if end == self.last {
+                return cidrs;
+            }
+            start = end + 1;
+        }
+    }
+    pub fn to_pretty_string(&self) -> String {
+        format!(
+            "{}-{}",
+            Cidr::format_address(self.family, self.first),
+            Cidr::format_address(self.family, self.last)
+        )
+    }
+}
+
+/// Parse one line of input, either a CIDR or a range of addresses.
+pub fn parse_line(s: &str) -> Result<Vec<Cidr>, ParseError> {
+    if s.contains('-') {
+        Ok(Range::parse(s)?.cidrs())
+    } else {
+        Ok(vec![Cidr::parse(s)?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_line, Cidr, ParseError, Range};
+    use std::collections::HashSet;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn bits(s: &str) -> Vec<bool> {
+        s.chars().map(|c| c != '0').collect()
+    }
+
+    fn cidr_bits(s: &str) -> Vec<bool> {
+        let cidr = Cidr::parse(s).unwrap();
+        (0..cidr.size()).map(|i| cidr.bit(i)).collect()
+    }
+
+    #[test]
+    fn check_cidr_to_bits() {
+        assert_eq!(
+            cidr_bits("0.0.0.0/32"),
+            bits("00000000000000000000000000000000")
+        );
+        assert_eq!(
+            cidr_bits("255.0.255.0/32"),
+            bits("11111111000000001111111100000000")
+        );
+        assert_eq!(
+            cidr_bits("1.1.1.1/32"),
+            bits("00000001000000010000000100000001")
+        );
+        assert_eq!(
+            cidr_bits("1.2.3.4/32"),
+            bits("00000001000000100000001100000100")
+        );
+        assert_eq!(
+            cidr_bits("3.5.7.9/32"),
+            bits("00000011000001010000011100001001")
+        );
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(
+            Cidr::parse("1.2.3.4/8").unwrap().to_pretty_string(),
+            "1.0.0.0/8"
+        );
+        assert_eq!(
+            Cidr::parse("42.43.44.45/24").unwrap().to_pretty_string(),
+            "42.43.44.0/24"
+        );
+        assert_eq!(
+            Cidr::parse("255.255.255.255/32")
+                .unwrap()
+                .to_pretty_string(),
+            "255.255.255.255/32"
+        );
+    }
+
+    #[test]
+    fn cidr_parse_v6() {
+        assert_eq!(
+            Cidr::parse("2001:db8:0:0:1:0:0:1/128")
+                .unwrap()
+                .to_pretty_string(),
+            "2001:db8::1:0:0:1/128"
+        );
+        assert_eq!(
+            Cidr::parse("2001:DB8:ffff::/32")
+                .unwrap()
+                .to_pretty_string(),
+            "2001:db8::/32"
+        );
+        assert_eq!(Cidr::parse("::/0").unwrap().to_pretty_string(), "::/0");
+        assert_eq!(
+            Cidr::parse("fe80::1:2/64").unwrap().to_pretty_string(),
+            "fe80::/64"
+        );
+    }
+
+    #[test]
+    fn cidr_parse_bare_address() {
+        assert_eq!(
+            Cidr::parse("203.0.113.7").unwrap().to_pretty_string(),
+            "203.0.113.7/32"
+        );
+        assert_eq!(
+            Cidr::parse("2001:db8::7").unwrap().to_pretty_string(),
+            "2001:db8::7/128"
+        );
+    }
+
+    #[test]
+    fn cidr_parse_mask() {
+        let pretty = |s| Cidr::parse(s).unwrap().to_pretty_string();
+
+        assert_eq!(pretty("10.0.0.0/255.255.255.0"), "10.0.0.0/24");
+        assert_eq!(pretty("10.0.0.0 255.255.0.0"), "10.0.0.0/16");
+        assert_eq!(pretty("10.0.0.0/0.0.0.255"), "10.0.0.0/24");
+        assert_eq!(pretty("10.0.0.0  0.0.3.255"), "10.0.0.0/22");
+        assert_eq!(pretty("10.0.0.1 255.255.255.255"), "10.0.0.1/32");
+        // An all-zero mask is the default route's netmask, and an all-ones
+        // mask on 0.0.0.0 could be a host or everything
+        assert_eq!(pretty("10.0.0.1 0.0.0.0"), "0.0.0.0/0");
+        assert_eq!(pretty("0.0.0.0/0.0.0.0"), "0.0.0.0/0");
+        assert_eq!(
+            Cidr::parse("0.0.0.0 255.255.255.255"),
+            Err(ParseError::AmbiguousMask("255.255.255.255".to_string()))
+        );
+        assert_eq!(pretty("0.0.0.0/32"), "0.0.0.0/32");
+        assert_eq!(pretty("10.0.0.0 8"), "10.0.0.0/8");
+        assert_eq!(
+            Cidr::parse("10.0.0.0/255.0.255.0").unwrap_err(),
+            ParseError::NonContiguousMask("255.0.255.0".to_string())
+        );
+        assert_eq!(
+            Cidr::parse("10.0.0.0 0.0.255.7").unwrap_err(),
+            ParseError::NonContiguousMask("0.0.255.7".to_string())
+        );
+    }
+
+    #[test]
+    fn range_to_cidrs() {
+        let cidrs = |s| {
+            parse_line(s)
+                .unwrap()
+                .iter()
+                .map(Cidr::to_pretty_string)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            cidrs("10.0.0.3-10.0.0.17"),
+            ["10.0.0.3/32", "10.0.0.4/30", "10.0.0.8/29", "10.0.0.16/31"]
+        );
+        assert_eq!(cidrs("192.0.2.0-192.0.2.255"), ["192.0.2.0/24"]);
+        assert_eq!(cidrs("0.0.0.0-255.255.255.255"), ["0.0.0.0/0"]);
+        assert_eq!(
+            cidrs("::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"),
+            ["::/0"]
+        );
+        assert_eq!(
+            cidrs("2001:db8::1-2001:db8::2"),
+            ["2001:db8::1/128", "2001:db8::2/128"]
+        );
+        assert_eq!(
+            Range::parse("10.0.0.2-10.0.0.1"),
+            Err(ParseError::InvalidRange("10.0.0.2-10.0.0.1".to_string()))
+        );
+        assert_eq!(
+            Range::parse("10.0.0.2-::1"),
+            Err(ParseError::MixedFamilies("10.0.0.2-::1".to_string()))
+        );
+    }
+
+    #[test]
+    fn cidr_parse_errors() {
+        let err = |s| Cidr::parse(s).unwrap_err();
+
+        assert_eq!(
+            err("10.0.0.0/x"),
+            ParseError::InvalidPrefixLength("x".to_string())
+        );
+        assert_eq!(
+            err("10.0.0.0/33"),
+            ParseError::PrefixLengthTooLong { size: 33, max: 32 }
+        );
+        assert_eq!(
+            err("10.0.0.300/32"),
+            ParseError::InvalidOctet("300".to_string())
+        );
+        assert_eq!(
+            err("10.-1.0.0/16"),
+            ParseError::InvalidOctet("-1".to_string())
+        );
+        assert_eq!(
+            err("10.+1.0.0/16"),
+            ParseError::InvalidOctet("+1".to_string())
+        );
+        assert_eq!(
+            err("010.0.0.1"),
+            ParseError::InvalidOctet("010".to_string())
+        );
+        assert_eq!(err("10.0.0/24"), ParseError::WrongOctetCount(3));
+        assert_eq!(
+            err("2001:db8::/129"),
+            ParseError::PrefixLengthTooLong {
+                size: 129,
+                max: 128
+            }
+        );
+        assert_eq!(
+            err("2001:db8:::/32"),
+            ParseError::InvalidIpv6("2001:db8:::".to_string())
+        );
+    }
+
+    #[test]
+    fn cidr_traits() {
+        let a: Cidr = "10.0.0.0/8".parse().unwrap();
+        let b = Cidr::new(Ipv4Addr::new(10, 1, 2, 3), 8).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.to_string(), "10.0.0.0/8");
+        assert_eq!(format!("{:>12}", a), "  10.0.0.0/8");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+
+        let mut cidrs: Vec<Cidr> = ["::/0", "10.0.0.0/16", "10.0.0.0/8", "9.0.0.0/8"]
+            .iter()
+            .map(|c| c.parse().unwrap())
+            .collect();
+        cidrs.sort();
+        let sorted: Vec<String> = cidrs.iter().map(Cidr::to_string).collect();
+        assert_eq!(sorted, ["9.0.0.0/8", "10.0.0.0/8", "10.0.0.0/16", "::/0"]);
+
+        let unique: HashSet<Cidr> = cidrs.iter().cloned().chain(vec![a]).collect();
+        assert_eq!(unique.len(), 4);
+    }
+
+    #[test]
+    fn cidr_std_net() {
+        let host = Cidr::from(Ipv4Addr::new(192, 0, 2, 7));
+        assert_eq!(host.to_string(), "192.0.2.7/32");
+        assert_eq!(Cidr::from(Ipv6Addr::LOCALHOST).to_string(), "::1/128");
+
+        let cidr = Cidr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 32).unwrap();
+        assert_eq!(cidr.to_string(), "2001:db8::/32");
+        let (address, size) = cidr.into();
+        assert_eq!(address, "2001:db8::".parse::<IpAddr>().unwrap());
+        assert_eq!(size, 32);
+
+        assert_eq!(
+            Cidr::new(Ipv4Addr::LOCALHOST, 33),
+            Err(ParseError::PrefixLengthTooLong { size: 33, max: 32 })
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! Merge lists of IPv4 and IPv6 CIDRs into the smallest equivalent list, and
+//! optionally approximate them with fewer, wider CIDRs.
+//!
+//! ```
+//! use cidrmerge::{Cidr, CidrSet};
+//!
+//! let mut set = CidrSet::new();
+//! set.insert(&Cidr::parse("10.0.0.0/24").unwrap());
+//! set.insert(&Cidr::parse("10.0.1.0/24").unwrap());
+//! set.insert(&Cidr::parse("10.0.3.0/24").unwrap());
+//!
+//! let merged: Vec<String> = set.iter().map(|c| c.to_pretty_string()).collect();
+//! assert_eq!(merged, ["10.0.0.0/23", "10.0.3.0/24"]);
+//!
+//! set.approximate(1);
+//! assert_eq!(set.iter().next().unwrap().to_pretty_string(), "10.0.0.0/22");
+//! ```
+
+mod cidr;
+mod map;
+mod optimal;
+mod set;
+mod tree;
+
+pub use crate::cidr::{parse_line, Cidr, Family, ParseError, Range};
+pub use crate::map::{Entries, PrefixMap};
+pub use crate::set::{CidrSet, Iter, Overlap, Stop};
@@ -1,323 +1,1170 @@
-use std::cmp::Ordering::{Greater, Less};
+use std::env;
+use std::fs;
 use std::io;
 use std::io::BufRead;
+use std::path::{Path, PathBuf};
 
-#[derive(Clone, Debug)]
-struct Cidr {
-    pub bits: Vec<bool>,
+use cidrmerge::{parse_line, Cidr, CidrSet, Family, Overlap, PrefixMap, Stop};
+
+/// Split a line of input into the entry and its comment, if any. Comments
+/// start with `#` or `;`.
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match line.find(['#', ';']) {
+        Some(i) => (line[..i].trim(), Some(line[i + 1..].trim())),
+        None => (line.trim(), None),
+    }
 }
 
-impl Cidr {
-    fn get_bits(ipv4cidr: &str, size: usize) -> Vec<bool> {
-        ipv4cidr
-            .split('.')
-            .flat_map(|group| {
-                let g: i16 = group.parse().unwrap();
-                [7, 6, 5, 4, 3, 2, 1, 0]
-                    .iter()
-                    .map(|b| g & (1 << b) > 0)
-                    .collect::<Vec<bool>>()
-            })
-            .take(size)
-            .collect()
-    }
-    fn bits(&self) -> &Vec<bool> {
-        &self.bits
-    }
-    fn size(&self) -> usize {
-        self.bits.len()
-    }
-    fn parse(s: &str) -> Self {
-        let mut x = s.split('/');
-        let ipv4cidr = x.next().unwrap();
-        let size = x.next().unwrap().parse().unwrap();
-        Cidr {
-            bits: Self::get_bits(ipv4cidr, size),
-        }
+/// Split a labelled entry into the entry and its label, the last word. A
+/// last word that is an address, such as the mask in
+/// `10.0.0.0 255.255.255.0`, is not a label, and neither is one without
+/// which the rest fails to parse while the whole entry parses.
+fn split_label(entry: &str) -> Option<(&str, &str)> {
+    let (rest, label) = entry.rsplit_once(char::is_whitespace)?;
+    let rest = rest.trim_end();
+    if Cidr::parse(label).is_ok() {
+        return None;
     }
-    fn push(&self, b: bool) -> Self {
-        let mut new = self.clone();
-        new.bits.push(b);
-        new
-    }
-    fn to_pretty_string(&self) -> String {
-        let mut groups = vec![0, 0, 0, 0];
-        let bits = self.bits();
-        for (i, x) in bits.iter().enumerate() {
-            if *x {
-                groups[i / 8] |= 1 << (7 - (i & 7));
-            }
-        }
-        format!(
-            "{}.{}.{}.{}/{}",
-            groups[0],
-            groups[1],
-            groups[2],
-            groups[3],
-            self.bits.len()
-        )
+    if parse_line(rest).is_err() && parse_line(entry).is_ok() {
+        return None;
     }
+    Some((rest, label))
+}
+
+/// Give `cidr` the label `label` and return the CIDRs with a different
+/// label it overlapped.
+fn insert_labelled(map: &mut PrefixMap<String>, cidr: &Cidr, label: &str) -> Vec<String> {
+    let conflicts = map
+        .overlapping(cidr)
+        .filter(|(_, other)| *other != label)
+        .map(|(other, other_label)| format!("{} {}", other.to_pretty_string(), other_label))
+        .collect();
+    map.insert(cidr, label.to_string());
+    conflicts
+}
+
+const USAGE: &str = "\
+usage: cidrmerge [options] [input...]
+       cidrmerge [options] union|intersect|difference|symdiff A B
+       cidrmerge [options] complement A [UNIVERSE]
+       cidrmerge [options] lookup SET [QUERIES]
+
+Merges the CIDRs read from the inputs and prints the result. An input is a
+file, a directory whose files are all read, or - for stdin, which is also
+the default when no inputs are given. Symlinked directories inside a
+directory are skipped.
+
+The set operations read A and B separately and print the addresses in
+either, in both, in A but not B, or in exactly one of them. complement
+prints the addresses in UNIVERSE but not in A, where the universe defaults
+to 0.0.0.0/0, plus ::/0 if A has IPv6. Their results are exact unless
+-n, --max-extra or --subset is given.
+
+lookup merges SET like the set operations do and reads one address or
+prefix per line from QUERIES, stdin by default. For each it prints the
+query, whether the set covers it fully, partially or not at all, and the
+merged CIDR matching it if fully covered.
+
+Bare addresses are read as a single host (/32 or /128) and ranges such as
+10.0.0.3-10.0.0.17 are split into CIDRs. IPv4 prefixes may also be given as
+a netmask or wildcard mask, e.g. 10.0.0.0/255.255.255.0 or 10.0.0.0 0.0.0.255.
+The mask 0.0.0.0 is read as /0, and 0.0.0.0 255.255.255.255 is refused as
+it could mean a single host or everything.
+Everything after a `#` or `;` is a comment and blank lines are ignored.
+
+With --labels every entry ends in a label, e.g. 10.0.0.0/24 customer-a or
+10.0.0.1 65001, which is the last word and may not be an address.
+Only CIDRs with the same label are merged, each output CIDR is printed with
+its label, and entries overlapping others with a different label are
+reported on stderr; the later entry wins for the addresses they share.
+
+options:
+  -n, --max-cidrs N     approximate until at most N CIDRs remain (default 40,
+                        unless only --max-extra is given)
+      --max-extra N[%]  approximate without covering more than N addresses
+                        that were not in the input, or N percent of the
+                        input addresses
+      --subset          keep the N CIDRs with the most addresses and drop the
+                        rest instead of widening, listing the dropped ones on
+                        stderr
+      --optimal         approximate with the fewest possible extra addresses
+                        instead of greedily; slower on large inputs
+      --max-width /N    never approximate to an IPv4 prefix shorter than /N
+      --max-width6 /N   never approximate to an IPv6 prefix shorter than /N
+      --exclude FILE    never approximate over the addresses in FILE, which
+                        is read like an input; may be given more than once
+      --compare         report the extra addresses of greedy and optimal
+                        approximation on stderr
+      --exact           only merge losslessly: the output covers exactly the
+                        input addresses (alias: --no-approximate)
+      --ranges          print contiguous address ranges instead of CIDRs
+      --labels          read a label after each entry and merge exactly,
+                        per label
+      --skip-invalid    report and skip lines that fail to parse instead of
+                        stopping at the first one
+      --stats[=FORMAT]  print statistics on stderr as text (default) or json
+      --explain         list the input lines and comments behind each output
+                        CIDR on stderr
+  -h, --help            show this help";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    /// Only collapse complete siblings and drop contained CIDRs, so the
+    /// output covers exactly the input addresses.
+    Exact,
+    /// Additionally widen CIDRs until at most `max_cidrs` remain, if given,
+    /// without adding more than `max_extra` addresses, if given.
+    Approximate {
+        max_cidrs: Option<usize>,
+        max_extra: Option<Extra>,
+    },
+    /// Drop all but this many CIDRs, so the output covers a subset of the
+    /// input addresses.
+    Subset(usize),
 }
 
-#[derive(Debug)]
-struct Tree {
-    present: bool,
-    pub node_count: usize,
-    pub cidr_count: usize,
-    pub coverage: f64,
-    pub cidr: Cidr,
-    pub left: Option<Box<Tree>>,
-    pub right: Option<Box<Tree>>,
-    pub best_coverage: Option<(f64, usize, Cidr)>,
+/// Budget of addresses an approximation may add to the input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Extra {
+    Addresses(u128),
+    /// A fraction of the number of input addresses.
+    Fraction(f64),
 }
 
-impl Tree {
-    fn new_node(cidr: Cidr) -> Self {
-        Tree {
-            cidr,
-            present: false,
-            cidr_count: 0,
-            node_count: 1,
-            coverage: 0.0,
-            left: None,
-            right: None,
-            best_coverage: None,
+impl Extra {
+    fn parse(s: &str) -> Option<Self> {
+        match s.strip_suffix('%') {
+            Some(percent) => percent
+                .parse::<f64>()
+                .ok()
+                .filter(|p| *p >= 0.0 && p.is_finite())
+                .map(|p| Extra::Fraction(p / 100.0)),
+            None => s.parse().ok().map(Extra::Addresses),
         }
     }
 
-    fn new() -> Self {
-        Tree::new_node(Cidr::parse("0.0.0.0/0"))
+    /// The budget in addresses for an input of `addresses` addresses.
+    fn resolve(self, addresses: u128) -> u128 {
+        match self {
+            Extra::Addresses(n) => n,
+            Extra::Fraction(f) => (f * addresses as f64) as u128,
+        }
     }
+}
 
-    fn make_present(&mut self) {
-        self.present = true;
+/// A set operation between inputs, run instead of merging them all.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    Union,
+    Intersection,
+    Difference,
+    SymmetricDifference,
+    Complement,
+    Lookup,
+}
 
-        // Remove any children as this new CIDR has full coverage anyway
-        self.left = None;
-        self.right = None;
+impl Command {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "union" => Some(Command::Union),
+            "intersect" => Some(Command::Intersection),
+            "difference" => Some(Command::Difference),
+            "symdiff" => Some(Command::SymmetricDifference),
+            "complement" => Some(Command::Complement),
+            "lookup" => Some(Command::Lookup),
+            _ => None,
+        }
     }
 
-    fn optimize(&mut self) {
-        let all_childs_present = [self.left.as_ref(), self.right.as_ref()]
-            .iter()
-            .all(|o| o.map(|t| t.present).unwrap_or(false));
-
-        if all_childs_present {
-            // Replace childs
-            self.make_present()
+    /// The number of inputs taken, at least and at most.
+    fn inputs(self) -> (usize, usize) {
+        match self {
+            Command::Complement | Command::Lookup => (1, 2),
+            _ => (2, 2),
         }
     }
 
-    fn update_coverage(&mut self) {
-        let childs = [self.left.as_ref(), self.right.as_ref()]
-            .iter()
-            .flatten()
-            .map(|t| t.coverage)
-            .sum::<f64>();
-        self.coverage = if self.present { 1.0 } else { childs / 2.0 };
+    fn run(self, a: &CidrSet, b: Option<&CidrSet>) -> CidrSet {
+        let b = b.cloned().unwrap_or_else(|| {
+            let mut universe = CidrSet::new();
+            universe.insert(&Cidr::parse("0.0.0.0/0").unwrap());
+            if a.iter().any(|c| c.family() == Family::V6) {
+                universe.insert(&Cidr::parse("::/0").unwrap());
+            }
+            universe
+        });
+        match self {
+            Command::Union => a.union(&b),
+            Command::Intersection => a.intersection(&b),
+            Command::Difference => a.difference(&b),
+            Command::SymmetricDifference => a.symmetric_difference(&b),
+            Command::Complement => a.complement(&b),
+            // The queries are not read as a set
+            Command::Lookup => a.clone(),
+        }
     }
+}
 
-    fn update_node_count(&mut self) {
-        let childs = [self.left.as_ref(), self.right.as_ref()]
-            .iter()
-            .flatten()
-            .map(|t| t.node_count)
-            .sum::<usize>();
-        self.node_count = 1 + childs;
-    }
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StatsFormat {
+    Text,
+    Json,
+}
 
-    fn update_cidr_count(&mut self) {
-        let childs = [self.left.as_ref(), self.right.as_ref()]
-            .iter()
-            .flatten()
-            .map(|t| t.cidr_count)
-            .sum();
-        self.cidr_count = if self.present { 1 } else { childs };
-    }
+#[derive(Debug, PartialEq)]
+struct Options {
+    command: Option<Command>,
+    mode: Mode,
+    optimal: bool,
+    compare: bool,
+    exclude: Vec<String>,
+    max_width: Option<usize>,
+    max_width6: Option<usize>,
+    skip_invalid: bool,
+    ranges: bool,
+    labels: bool,
+    explain: bool,
+    stats: Option<StatsFormat>,
+    inputs: Vec<String>,
+}
 
-    fn update_best_coverage(&mut self) {
-        if self.present {
-            self.best_coverage = None;
-            return;
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let (mut exact, mut subset) = (false, false);
+        let (mut max_cidrs, mut max_extra) = (None, None);
+        let mut options = Options {
+            command: None,
+            mode: Mode::Exact,
+            optimal: false,
+            compare: false,
+            exclude: vec![],
+            max_width: None,
+            max_width6: None,
+            skip_invalid: false,
+            ranges: false,
+            labels: false,
+            explain: false,
+            stats: None,
+            inputs: vec![],
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--" => options.inputs.extend(args.by_ref()),
+                "-n" | "--max-cidrs" => {
+                    let value = args.next().ok_or(format!("{} needs a value", arg))?;
+                    let n = value
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or(format!("invalid count for {}: {}", arg, value))?;
+                    max_cidrs = Some(n);
+                    exact = false;
+                }
+                "--max-extra" => {
+                    let value = args.next().ok_or(format!("{} needs a value", arg))?;
+                    let extra = Extra::parse(&value)
+                        .ok_or(format!("invalid budget for {}: {}", arg, value))?;
+                    max_extra = Some(extra);
+                    exact = false;
+                }
+                "--exclude" => {
+                    let value = args.next().ok_or(format!("{} needs a value", arg))?;
+                    options.exclude.push(value);
+                }
+                "--max-width" | "--max-width6" => {
+                    let value = args.next().ok_or(format!("{} needs a value", arg))?;
+                    let family = if arg == "--max-width" {
+                        Family::V4
+                    } else {
+                        Family::V6
+                    };
+                    let size = value
+                        .strip_prefix('/')
+                        .unwrap_or(&value)
+                        .parse()
+                        .ok()
+                        .filter(|&size| size <= family.width())
+                        .ok_or(format!("invalid prefix length for {}: {}", arg, value))?;
+                    match family {
+                        Family::V4 => options.max_width = Some(size),
+                        Family::V6 => options.max_width6 = Some(size),
+                    }
+                }
+                "--optimal" => options.optimal = true,
+                "--compare" => options.compare = true,
+                "--exact" | "--no-approximate" => exact = true,
+                "--subset" => subset = true,
+                "--skip-invalid" => options.skip_invalid = true,
+                "--ranges" => options.ranges = true,
+                "--labels" => options.labels = true,
+                "--explain" => options.explain = true,
+                "--stats" | "--stats=text" => options.stats = Some(StatsFormat::Text),
+                "--stats=json" => options.stats = Some(StatsFormat::Json),
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                _ if options.command.is_none()
+                    && options.inputs.is_empty()
+                    && Command::parse(&arg).is_some() =>
+                {
+                    options.command = Command::parse(&arg)
+                }
+                _ if arg == "-" || !arg.starts_with('-') => options.inputs.push(arg),
+                _ => return Err(format!("unknown argument: {}\n\n{}", arg, USAGE)),
+            }
+        }
+        if subset {
+            let widening = options.optimal
+                || options.compare
+                || !options.exclude.is_empty()
+                || options.max_width.is_some()
+                || options.max_width6.is_some();
+            if widening || max_extra.is_some() {
+                return Err(
+                    "--subset only takes a count and no --max-extra, --optimal, \
+                            --compare, --exclude, --max-width or --max-width6"
+                        .to_string(),
+                );
+            }
+        }
+        if let Some(command) = options.command {
+            let (min, max) = command.inputs();
+            if options.inputs.len() < min || options.inputs.len() > max {
+                return Err(format!(
+                    "wrong number of inputs for the set operation\n\n{}",
+                    USAGE
+                ));
+            }
+            // Set operations are exact unless asked otherwise
+            exact |= max_cidrs.is_none() && max_extra.is_none() && !subset;
+        }
+        if options.labels {
+            let approximate = max_cidrs.is_some() || max_extra.is_some() || subset;
+            if options.command.is_some() || approximate || !options.exclude.is_empty() {
+                return Err("--labels only merges exactly and takes no set operation, \
+                            -n, --max-extra, --subset or --exclude"
+                    .to_string());
+            }
+            if options.ranges || options.stats.is_some() {
+                return Err("--labels cannot be combined with --ranges or --stats".to_string());
+            }
+            exact = true;
         }
+        if subset && !exact {
+            options.mode = Mode::Subset(max_cidrs.unwrap_or(40));
+        } else if !exact {
+            options.mode = Mode::Approximate {
+                max_cidrs: max_cidrs.or(if max_extra.is_none() { Some(40) } else { None }),
+                max_extra,
+            };
+        }
+        if options.inputs.is_empty() {
+            options.inputs.push("-".to_string());
+        }
+        Ok(options)
+    }
+}
 
-        let me = Some((self.coverage(), self.cidr_count, self.cidr.clone()));
-        let left = self.left.as_ref().and_then(|t| t.best_coverage.as_ref());
-        let right = self.right.as_ref().and_then(|t| t.best_coverage.as_ref());
-        let all = [me.as_ref(), left, right];
+/// All addresses in the set, IPv4 and IPv6 together.
+fn addresses(set: &CidrSet) -> u128 {
+    set.addresses(Family::V4)
+        .saturating_add(set.addresses(Family::V6))
+}
 
-        let candidates = all
-            .iter()
-            .flatten()
-            .cloned();
-
-        let score = |s: i32, f: f64| 2.0_f64.powi(32 - s) * (1.0 - f);
-        self.best_coverage = candidates
-            .min_by(|a, b| {
-                if score(a.2.size() as i32, a.0) < score(b.2.size() as i32, b.0) {
-                    Less
-                } else {
-                    Greater
+/// Approximate copies of the set both ways and report the difference.
+fn compare(set: &CidrSet, max_cidrs: Option<usize>, max_extra: u128) {
+    let (mut greedy, mut optimal) = (set.clone(), set.clone());
+    let (greedy_extra, _) = greedy.approximate_within(max_cidrs, max_extra);
+    let (optimal_extra, _) = optimal.approximate_optimal_within(max_cidrs, max_extra);
+
+    eprintln!(
+        "greedy: {} cidrs, {} extra addresses",
+        greedy.len(),
+        greedy_extra
+    );
+    eprintln!(
+        "optimal: {} cidrs, {} extra addresses",
+        optimal.len(),
+        optimal_extra
+    );
+}
+
+/// An input line kept around for the explain report.
+struct Entry {
+    source: String,
+    line: usize,
+    text: String,
+    comment: Option<String>,
+    cidrs: Vec<Cidr>,
+}
+
+/// Print each output CIDR, sorted, followed by the input entries it covers.
+fn explain(cidrs: &[Cidr], entries: &[Entry]) {
+    // The output CIDRs are disjoint and sorted, so each input CIDR is covered
+    // by the last output CIDR starting at or before it
+    let mut covered: Vec<Vec<&Entry>> = cidrs.iter().map(|_| vec![]).collect();
+    for entry in entries {
+        for cidr in &entry.cidrs {
+            let key = |c: &Cidr| (c.family(), c.first());
+            let i = cidrs.partition_point(|c| key(c) <= key(cidr));
+            let found = i.checked_sub(1).filter(|&i| cidrs[i].contains(cidr));
+            if let Some(list) = found.map(|i| &mut covered[i]) {
+                if !list.last().is_some_and(|e| std::ptr::eq(*e, entry)) {
+                    list.push(entry);
                 }
-            })
-            .cloned()
+            }
+        }
     }
 
-    fn insert_bits(&mut self, bits: &[bool]) {
-        if self.present {
-            return;
+    for (cidr, entries) in cidrs.iter().zip(covered) {
+        eprintln!("{}", cidr.to_pretty_string());
+        for entry in entries {
+            match &entry.comment {
+                Some(comment) => eprintln!(
+                    "    {}:{}: {} # {}",
+                    entry.source, entry.line, entry.text, comment
+                ),
+                None => eprintln!("    {}:{}: {}", entry.source, entry.line, entry.text),
+            }
         }
+    }
+}
 
-        if let Some(bit) = bits.first().cloned() {
-            // Get or create the child we need to go to
-            let next = self.cidr.push(bit);
-            let opt_child = if bit { &mut self.right } else { &mut self.left };
-            let child = opt_child.get_or_insert_with(|| Box::new(Tree::new_node(next)));
-            // Recursive insert
-            child.insert_bits(&bits[1..]);
-        } else {
-            // We traversed the full path so this node is the one we want
-            self.make_present()
+/// Summary of a run, printed on stderr with `--stats`.
+struct Stats<'a> {
+    coverage: f64,
+    coverage6: f64,
+    nodes: usize,
+    cidrs: usize,
+    extra: u128,
+    lost: u128,
+    sources: &'a [(String, usize)],
+}
+
+impl Stats<'_> {
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "coverage: {}\ncoverage6: {}\nnodes: {}\ncidrs: {}\nextra: {}\nlost: {}\n",
+            self.coverage, self.coverage6, self.nodes, self.cidrs, self.extra, self.lost
+        );
+        for (name, count) in self.sources {
+            text += &format!("source {}: {}\n", name, count);
         }
+        text
+    }
 
-        self.optimize();
-        self.update_cidr_count();
-        self.update_node_count();
-        self.update_coverage();
-        self.update_best_coverage();
+    fn to_json(&self) -> String {
+        let sources = self
+            .sources
+            .iter()
+            .map(|(name, count)| format!("{{\"name\":{},\"cidrs\":{}}}", json_string(name), count))
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"coverage\":{},\"coverage6\":{},\"nodes\":{},\"cidrs\":{},\"extra\":{},\"lost\":{},\"sources\":[{}]}}\n",
+            self.coverage,
+            self.coverage6,
+            self.nodes,
+            self.cidrs,
+            self.extra,
+            self.lost,
+            sources.join(",")
+        )
     }
+}
 
-    fn nodes(&self) -> usize {
-        self.node_count
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
     }
+    out + "\""
+}
 
-    fn cidrs(&self) -> usize {
-        self.cidr_count
+/// Expand the inputs given on the command line into the files to read,
+/// walking directories recursively in name order. Symlinked directories met
+/// on the way are skipped, so a link cannot make the walk loop.
+fn expand_inputs(inputs: &[String]) -> Result<Vec<PathBuf>, String> {
+    fn walk(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+        if !path.is_dir() {
+            files.push(path.to_path_buf());
+            return Ok(());
+        }
+        let error = |e: io::Error| format!("{}: {}", path.display(), e);
+        let mut children = fs::read_dir(path)
+            .map_err(error)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(error)?;
+        children.retain(|child| {
+            let link = fs::symlink_metadata(child).is_ok_and(|m| m.file_type().is_symlink());
+            !(link && child.is_dir())
+        });
+        children.sort();
+        children.iter().try_for_each(|child| walk(child, files))
     }
 
-    fn insert(&mut self, cidr: &Cidr) {
-        let bits = cidr.bits();
-        self.insert_bits(&bits.as_slice()[..cidr.size()]);
+    let mut files = vec![];
+    for input in inputs {
+        walk(Path::new(input), &mut files)?;
     }
+    Ok(files)
+}
 
-    fn coverage(&self) -> f64 {
-        self.coverage
+/// Fail with `message` about an invalid line, or report it and carry on with
+/// `--skip-invalid`.
+fn invalid(message: String, options: &Options) -> Result<(), String> {
+    if !options.skip_invalid {
+        return Err(message);
     }
+    eprintln!("{}", message);
+    Ok(())
+}
 
-    fn best_coverage(&self) -> Option<&(f64, usize, Cidr)> {
-        self.best_coverage.as_ref()
+/// Decode line `i` of `name`, read as raw bytes. A line that is not UTF-8 is
+/// invalid like one that fails to parse, and is `None` when skipped.
+fn decode_line(
+    name: &str,
+    i: usize,
+    line: io::Result<Vec<u8>>,
+    options: &Options,
+) -> Result<Option<String>, String> {
+    let bytes = line.map_err(|e| format!("{}:{}: {}", name, i + 1, e))?;
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(Some(s)),
+        Err(_) => invalid(format!("{}:{}: invalid UTF-8", name, i + 1), options).map(|()| None),
     }
+}
+
+/// Where the entries read from the inputs go.
+enum Target<'a> {
+    Set(&'a mut CidrSet),
+    /// Labelled entries, with `--labels`.
+    Labels(&'a mut PrefixMap<String>),
+}
 
-    fn print(&self) {
-        if self.present {
-            println!("{}", self.cidr.to_pretty_string());
+/// Insert every entry read from `reader` into the target and return the
+/// number of CIDRs it contributed.
+fn read_input(
+    name: &str,
+    reader: impl BufRead,
+    target: &mut Target,
+    entries: &mut Vec<Entry>,
+    options: &Options,
+) -> Result<usize, String> {
+    let mut count = 0;
+    for (i, line) in reader.split(b'\n').enumerate() {
+        let s = match decode_line(name, i, line, options)? {
+            Some(s) => s,
+            None => continue,
+        };
+        let (entry, comment) = split_comment(&s);
+        if entry.is_empty() {
+            continue;
         }
+        let parsed = match target {
+            Target::Set(_) => parse_line(entry)
+                .map(|cidrs| (cidrs, ""))
+                .map_err(|e| e.to_string()),
+            Target::Labels(_) => split_label(entry)
+                .ok_or_else(|| "missing label".to_string())
+                .and_then(|(cidr, label)| {
+                    parse_line(cidr)
+                        .map(|cidrs| (cidrs, label))
+                        .map_err(|e| e.to_string())
+                }),
+        };
+        match parsed {
+            Ok((cidrs, label)) => {
+                match target {
+                    Target::Set(set) => cidrs.iter().for_each(|cidr| set.insert(cidr)),
+                    Target::Labels(map) => {
+                        for cidr in &cidrs {
+                            for other in insert_labelled(map, cidr, label) {
+                                eprintln!(
+                                    "{}:{}: {} {} conflicts with {}",
+                                    name,
+                                    i + 1,
+                                    cidr.to_pretty_string(),
+                                    label,
+                                    other
+                                );
+                            }
+                        }
+                    }
+                }
+                count += cidrs.len();
+                if options.explain {
+                    entries.push(Entry {
+                        source: name.to_string(),
+                        line: i + 1,
+                        text: entry.to_string(),
+                        comment: comment.map(str::to_string),
+                        cidrs,
+                    });
+                }
+            }
+            Err(e) => {
+                invalid(format!("{}:{}: {:?}: {}", name, i + 1, entry, e), options)?;
+            }
+        }
+    }
+    Ok(count)
+}
 
-        [self.left.as_ref(), self.right.as_ref()]
-            .iter()
-            .flatten()
-            .for_each(|t| t.print());
+/// Read all inputs into the target and return the number of CIDRs each
+/// source contributed.
+fn read_inputs(
+    inputs: &[String],
+    target: &mut Target,
+    entries: &mut Vec<Entry>,
+    options: &Options,
+) -> Result<Vec<(String, usize)>, String> {
+    let mut sources = vec![];
+    for path in expand_inputs(inputs)? {
+        let name = if path == Path::new("-") {
+            "<stdin>".to_string()
+        } else {
+            path.display().to_string()
+        };
+        let count = if path == Path::new("-") {
+            let stdin = io::stdin();
+            let reader = stdin.lock();
+            read_input(&name, reader, target, entries, options)?
+        } else {
+            let file = fs::File::open(&path).map_err(|e| format!("{}: {}", name, e))?;
+            read_input(&name, io::BufReader::new(file), target, entries, options)?
+        };
+        sources.push((name, count));
     }
+    Ok(sources)
+}
 
-    fn print_tree(&self, indent: String) {
-        if self.present {
-            println!("{} {}", indent, self.cidr.to_pretty_string());
+/// Print how `set` covers each address or prefix read from `reader`.
+fn lookup(
+    name: &str,
+    reader: impl BufRead,
+    set: &CidrSet,
+    options: &Options,
+) -> Result<(), String> {
+    for (i, line) in reader.split(b'\n').enumerate() {
+        let s = match decode_line(name, i, line, options)? {
+            Some(s) => s,
+            None => continue,
+        };
+        let (entry, _) = split_comment(&s);
+        if entry.is_empty() {
+            continue;
+        }
+        match Cidr::parse(entry) {
+            Ok(cidr) => match set.longest_match(&cidr) {
+                Some(found) => println!("{} full {}", entry, found.to_pretty_string()),
+                None if set.overlap(&cidr) == Overlap::Partial => println!("{} partial", entry),
+                None => println!("{} none", entry),
+            },
+            Err(e) => {
+                invalid(format!("{}:{}: {:?}: {}", name, i + 1, entry, e), options)?;
+            }
         }
-
-        [("0", self.left.as_ref()), ("1", self.right.as_ref())]
-            .iter()
-            .map(|(d, o)| o.map(|t| (d, t)))
-            .flatten()
-            .for_each(|(d, t)| t.print_tree(indent.clone() + d));
     }
+    Ok(())
 }
 
 fn main() {
-    let mut tree = Tree::new();
-    let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        if let Ok(s) = line {
-            let cidr = Cidr::parse(&s);
-            tree.insert(&cidr);
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    let fail = |message: String| -> ! {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    };
+
+    let mut entries = vec![];
+    if options.labels {
+        let mut map = PrefixMap::new();
+        read_inputs(
+            &options.inputs,
+            &mut Target::Labels(&mut map),
+            &mut entries,
+            &options,
+        )
+        .unwrap_or_else(|e| fail(e));
+        if options.explain {
+            explain(&map.iter().map(|(c, _)| c).collect::<Vec<_>>(), &entries);
+        }
+        for (cidr, label) in &map {
+            println!("{} {}", cidr.to_pretty_string(), label);
         }
+        return;
+    }
+
+    let (mut set, sources) = match options.command {
+        None => {
+            let mut set = CidrSet::new();
+            let sources = read_inputs(
+                &options.inputs,
+                &mut Target::Set(&mut set),
+                &mut entries,
+                &options,
+            )
+            .unwrap_or_else(|e| fail(e));
+            (set, sources)
+        }
+        Some(command) => {
+            // Each input is a separate operand
+            let operands = match command {
+                Command::Lookup => &options.inputs[..1],
+                _ => &options.inputs[..],
+            };
+            let (mut sets, mut sources) = (vec![], vec![]);
+            for input in operands {
+                let mut set = CidrSet::new();
+                let read = read_inputs(
+                    std::slice::from_ref(input),
+                    &mut Target::Set(&mut set),
+                    &mut entries,
+                    &options,
+                )
+                .unwrap_or_else(|e| fail(e));
+                sources.extend(read);
+                sets.push(set);
+            }
+            (command.run(&sets[0], sets.get(1)), sources)
+        }
+    };
+    if !options.exclude.is_empty() {
+        let mut excluded = CidrSet::new();
+        read_inputs(
+            &options.exclude,
+            &mut Target::Set(&mut excluded),
+            &mut vec![],
+            &options,
+        )
+        .unwrap_or_else(|e| fail(e));
+        set.set_excluded(&excluded);
+    }
+    if let Some(size) = options.max_width {
+        set.set_min_size(Family::V4, size);
+    }
+    if let Some(size) = options.max_width6 {
+        set.set_min_size(Family::V6, size);
     }
 
-    while tree.cidrs() > 40 {
-        let best = tree.best_coverage().cloned();
+    let (mut extra, mut lost) = (0, 0);
+    if let Mode::Subset(max_cidrs) = options.mode {
+        for cidr in set.retain_largest(max_cidrs) {
+            eprintln!("lost: {}", cidr.to_pretty_string());
+            lost = cidr.count().saturating_add(lost);
+        }
+    }
+    if let Mode::Approximate {
+        max_cidrs,
+        max_extra,
+    } = options.mode
+    {
+        let max_extra = max_extra.map_or(u128::MAX, |e| e.resolve(addresses(&set)));
+        if options.compare {
+            compare(&set, max_cidrs, max_extra);
+        }
+        let (added, stop) = if options.optimal {
+            set.approximate_optimal_within(max_cidrs, max_extra)
+        } else {
+            set.approximate_within(max_cidrs, max_extra)
+        };
+        extra = added;
 
-        if let Some(pair) = best {
-            tree.insert(&pair.2)
+        // Falling short of the count because of the limits is reported,
+        // with or without a budget. Exclusions are about not blocking
+        // ourselves, so falling short of them is an error
+        let excluded = !options.exclude.is_empty();
+        let capped = options.max_width.is_some() || options.max_width6.is_some();
+        let blocked = stop == Stop::Blocked && (excluded || capped);
+        if let Some(max_cidrs) = max_cidrs.filter(|_| blocked) {
+            let limits = match (excluded, capped) {
+                (true, false) => "without covering excluded addresses",
+                (false, true) => "within the maximum width",
+                _ => "without covering excluded addresses and within the maximum width",
+            };
+            let message = format!(
+                "cannot approximate to {} CIDRs {}, the fewest possible is {}",
+                max_cidrs,
+                limits,
+                set.len()
+            );
+            if excluded {
+                fail(message);
+            }
+            eprintln!("{}", message);
         }
     }
 
-    println!("coverage: {}", tree.coverage());
-    println!("nodes: {}", tree.nodes());
-    println!("cidrs: {}", tree.cidrs());
+    if options.explain {
+        explain(&set.iter().collect::<Vec<_>>(), &entries);
+    }
+
+    if let Some(format) = options.stats {
+        let stats = Stats {
+            coverage: set.coverage(Family::V4),
+            coverage6: set.coverage(Family::V6),
+            nodes: set.nodes(),
+            cidrs: set.len(),
+            extra,
+            lost,
+            sources: &sources,
+        };
+        match format {
+            StatsFormat::Text => eprint!("{}", stats.to_text()),
+            StatsFormat::Json => eprint!("{}", stats.to_json()),
+        }
+    }
 
-    tree.print();
+    if options.command == Some(Command::Lookup) {
+        let name = options.inputs.get(1).map_or("-", String::as_str);
+        let result = if name == "-" {
+            let stdin = io::stdin();
+            let reader = stdin.lock();
+            lookup("<stdin>", reader, &set, &options)
+        } else {
+            fs::File::open(name)
+                .map_err(|e| format!("{}: {}", name, e))
+                .and_then(|file| lookup(name, io::BufReader::new(file), &set, &options))
+        };
+        result.unwrap_or_else(|e| fail(e));
+    } else if options.ranges {
+        for range in set.ranges() {
+            println!("{}", range.to_pretty_string());
+        }
+    } else {
+        for cidr in &set {
+            println!("{}", cidr.to_pretty_string());
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Cidr, Tree};
+    use super::{
+        expand_inputs, insert_labelled, read_input, read_inputs, split_comment, split_label,
+        Command, Extra, Mode, Options, Stats, StatsFormat, Target,
+    };
+    use cidrmerge::{Cidr, CidrSet, PrefixMap};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn options_parse() {
+        let parse = |args: &[&str]| Options::parse(args.iter().map(|a| a.to_string()));
+
+        let approximate = |max_cidrs, max_extra| Mode::Approximate {
+            max_cidrs,
+            max_extra,
+        };
+        assert_eq!(parse(&[]).unwrap().mode, approximate(Some(40), None));
+        assert_eq!(
+            parse(&["-n", "250"]).unwrap().mode,
+            approximate(Some(250), None)
+        );
+        assert_eq!(
+            parse(&["--max-cidrs", "16"]).unwrap().mode,
+            approximate(Some(16), None)
+        );
+        assert_eq!(parse(&["--exact"]).unwrap().mode, Mode::Exact);
+        assert_eq!(parse(&["--no-approximate"]).unwrap().mode, Mode::Exact);
+        assert_eq!(
+            parse(&["--exact", "-n", "5"]).unwrap().mode,
+            approximate(Some(5), None)
+        );
+        assert!(parse(&["-n"]).is_err());
+        assert!(parse(&["-n", "many"]).is_err());
+        assert!(parse(&["-n", "0"]).is_err());
+
+        assert_eq!(
+            parse(&["--max-extra", "4096"]).unwrap().mode,
+            approximate(None, Some(Extra::Addresses(4096)))
+        );
+        assert_eq!(
+            parse(&["--max-extra", "50%", "-n", "8"]).unwrap().mode,
+            approximate(Some(8), Some(Extra::Fraction(0.5)))
+        );
+        assert!(parse(&["--max-extra", "-1"]).is_err());
+        assert!(parse(&["--max-extra", "-1%"]).is_err());
+        assert_eq!(Extra::Fraction(0.001).resolve(1 << 20), 1048);
+
+        assert_eq!(
+            parse(&["--exclude", "office.txt", "in.txt", "--exclude", "-"])
+                .unwrap()
+                .exclude,
+            ["office.txt", "-"]
+        );
+        assert!(parse(&["--exclude"]).is_err());
+
+        assert_eq!(parse(&["--subset"]).unwrap().mode, Mode::Subset(40));
+        assert_eq!(
+            parse(&["-n", "3", "--subset"]).unwrap().mode,
+            Mode::Subset(3)
+        );
+        assert!(parse(&["--subset", "--max-extra", "10"]).is_err());
+        assert!(parse(&["--subset", "--optimal"]).is_err());
+        assert!(parse(&["--compare", "--subset"]).is_err());
+        assert!(parse(&["--subset", "--exclude", "office.txt"]).is_err());
+        assert!(parse(&["--subset", "--max-width6", "/48"]).is_err());
 
-    fn bits(s: &str) -> Vec<bool> {
-        s.chars()
-            .map(|c| if c == '0' { false } else { true })
-            .collect()
+        let options = parse(&["--max-width", "/16", "--max-width6", "48"]).unwrap();
+        assert_eq!(
+            (options.max_width, options.max_width6),
+            (Some(16), Some(48))
+        );
+        assert!(parse(&["--max-width", "/33"]).is_err());
+        assert!(parse(&["--max-width6", "/129"]).is_err());
+        assert!(parse(&["--max-width", "wide"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+
+        assert_eq!(parse(&[]).unwrap().inputs, ["-"]);
+        assert_eq!(
+            parse(&["a.txt", "-", "-n", "5", "dir"]).unwrap().inputs,
+            ["a.txt", "-", "dir"]
+        );
+        assert_eq!(parse(&["--", "--exact"]).unwrap().inputs, ["--exact"]);
+
+        let options = parse(&["difference", "block.txt", "allow.txt"]).unwrap();
+        assert_eq!(options.command, Some(Command::Difference));
+        assert_eq!(options.inputs, ["block.txt", "allow.txt"]);
+        assert_eq!(options.mode, Mode::Exact);
+        assert_eq!(
+            parse(&["-n", "5", "union", "a", "b"]).unwrap().mode,
+            approximate(Some(5), None)
+        );
+        assert_eq!(
+            parse(&["complement", "-"]).unwrap().command,
+            Some(Command::Complement)
+        );
+        assert_eq!(
+            parse(&["lookup", "set.txt"]).unwrap().command,
+            Some(Command::Lookup)
+        );
+        assert!(parse(&["union", "a"]).is_err());
+        assert!(parse(&["complement", "a", "b", "c"]).is_err());
+        // Only the first positional argument can be an operation
+        assert_eq!(parse(&["a", "union"]).unwrap().command, None);
+        assert_eq!(parse(&["--", "union"]).unwrap().inputs, ["union"]);
+
+        let options = parse(&["--labels", "acl.txt"]).unwrap();
+        assert!(options.labels);
+        assert_eq!(options.mode, Mode::Exact);
+        assert!(parse(&["--labels", "-n", "5"]).is_err());
+        assert!(parse(&["--labels", "--subset"]).is_err());
+        assert!(parse(&["--labels", "union", "a", "b"]).is_err());
+        assert!(parse(&["--labels", "--ranges"]).is_err());
+
+        assert_eq!(parse(&[]).unwrap().stats, None);
+        assert_eq!(parse(&["--stats"]).unwrap().stats, Some(StatsFormat::Text));
+        assert_eq!(
+            parse(&["--stats=json"]).unwrap().stats,
+            Some(StatsFormat::Json)
+        );
+    }
+
+    #[test]
+    fn stats_format() {
+        let sources = [("a \"b\".txt".to_string(), 3), ("<stdin>".to_string(), 1)];
+        let stats = Stats {
+            coverage: 0.5,
+            coverage6: 0.0,
+            nodes: 7,
+            cidrs: 2,
+            extra: 12,
+            lost: 0,
+            sources: &sources,
+        };
+
+        assert_eq!(
+            stats.to_text(),
+            "coverage: 0.5\ncoverage6: 0\nnodes: 7\ncidrs: 2\nextra: 12\nlost: 0\n\
+             source a \"b\".txt: 3\nsource <stdin>: 1\n"
+        );
+        assert_eq!(
+            stats.to_json(),
+            "{\"coverage\":0.5,\"coverage6\":0,\"nodes\":7,\"cidrs\":2,\"extra\":12,\"lost\":0,\"sources\":[\
+             {\"name\":\"a \\\"b\\\".txt\",\"cidrs\":3},{\"name\":\"<stdin>\",\"cidrs\":1}]}\n"
+        );
     }
 
     #[test]
-    fn check_cidr_to_bits() {
+    fn line_comments() {
+        assert_eq!(split_comment("10.0.0.0/8"), ("10.0.0.0/8", None));
+        assert_eq!(split_comment("  10.0.0.0/8\r"), ("10.0.0.0/8", None));
+        assert_eq!(split_comment("# office"), ("", Some("office")));
+        assert_eq!(split_comment("; office"), ("", Some("office")));
         assert_eq!(
-            Cidr::parse("0.0.0.0/32").bits(),
-            &bits("00000000000000000000000000000000")
+            split_comment("\t10.0.0.0/8 # office ; lab\r"),
+            ("10.0.0.0/8", Some("office ; lab"))
         );
+        assert_eq!(split_comment(" \r"), ("", None));
+    }
+
+    #[test]
+    fn line_labels() {
         assert_eq!(
-            Cidr::parse("255.0.255.0/32").bits(),
-            &bits("11111111000000001111111100000000")
+            split_label("10.0.0.0/24 customer-a"),
+            Some(("10.0.0.0/24", "customer-a"))
         );
         assert_eq!(
-            Cidr::parse("1.1.1.1/32").bits(),
-            &bits("00000001000000010000000100000001")
+            split_label("10.0.0.0 0.0.0.255\tcustomer-a"),
+            Some(("10.0.0.0 0.0.0.255", "customer-a"))
         );
         assert_eq!(
-            Cidr::parse("1.2.3.4/32").bits(),
-            &bits("00000001000000100000001100000100")
+            split_label("10.0.0.1 - 10.0.0.6  b"),
+            Some(("10.0.0.1 - 10.0.0.6", "b"))
         );
+        // A number is a label, not a prefix length
+        assert_eq!(split_label("10.0.0.1 7"), Some(("10.0.0.1", "7")));
         assert_eq!(
-            Cidr::parse("3.5.7.9/32").bits(),
-            &bits("00000011000001010000011100001001")
+            split_label("10.0.0.0/24 65001"),
+            Some(("10.0.0.0/24", "65001"))
         );
+        assert_eq!(split_label("10.0.0.0/24"), None);
+        assert_eq!(split_label("10.0.0.1 - 10.0.0.6"), None);
+        // A whitespace netmask is not a label
+        assert_eq!(split_label("10.0.0.0 255.255.255.0"), None);
+        assert_eq!(split_label("10.0.0.0 0.0.0.255"), None);
+    }
+
+    /// A fresh, empty directory for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cidrmerge-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
     }
 
     #[test]
-    fn tree_insert() {
-        let cidrs = vec![
-            Cidr::parse("255.0.0.0/8"),
-            Cidr::parse("255.100.0.0/16"),
-            Cidr::parse("254.100.0.0/16"),
-            Cidr::parse("13.14.15.16/32"),
-        ];
-        let mut tree = Tree::new();
+    fn inputs_walk() {
+        let dir = temp_dir("walk");
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::write(dir.join("a/z.txt"), "10.0.0.0/24\n").unwrap();
+        fs::write(dir.join("b.txt"), "10.0.1.0/24\n10.0.2.0/24\n").unwrap();
+        fs::write(dir.join("c.txt"), "# empty\n").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, dir.join("loop")).unwrap();
 
-        cidrs.iter().for_each(|c| tree.insert(c));
+        let inputs = ["-".to_string(), dir.display().to_string()];
+        let files = expand_inputs(&inputs).unwrap();
+        let expected = [
+            PathBuf::from("-"),
+            dir.join("a/z.txt"),
+            dir.join("b.txt"),
+            dir.join("c.txt"),
+        ];
+        assert_eq!(files, expected);
 
-        assert_eq!(tree.cidrs(), 3);
-        assert_eq!(tree.nodes(), 1 + 8 + 9 + 32);
+        let options = Options::parse(std::iter::empty()).unwrap();
+        let mut set = CidrSet::new();
+        let sources = read_inputs(
+            &inputs[1..],
+            &mut Target::Set(&mut set),
+            &mut vec![],
+            &options,
+        )
+        .unwrap();
+        let name = |p: &PathBuf| p.display().to_string();
         assert_eq!(
-            tree.coverage(),
-            1.0 / 256.0 + 1.0 / 65536.0 + 1.0 / 4294967296.0
+            sources,
+            [
+                (name(&expected[1]), 1),
+                (name(&expected[2]), 2),
+                (name(&expected[3]), 0)
+            ]
+        );
+        assert_eq!(set.len(), 2);
+
+        // Errors name the file and line
+        fs::write(dir.join("b.txt"), "10.0.1.0/24\nbogus\n").unwrap();
+        let error = read_inputs(
+            &inputs[1..],
+            &mut Target::Set(&mut CidrSet::new()),
+            &mut vec![],
+            &options,
+        )
+        .unwrap_err();
+        assert!(error.starts_with(&format!("{}:2: \"bogus\"", name(&expected[2]))));
+        assert!(expand_inputs(&[name(&dir.join("missing"))]).is_ok());
+        let missing = read_inputs(
+            &[name(&dir.join("missing"))],
+            &mut Target::Set(&mut CidrSet::new()),
+            &mut vec![],
+            &options,
         );
+        assert!(missing
+            .unwrap_err()
+            .starts_with(&name(&dir.join("missing"))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_invalid_utf8() {
+        let input: &[u8] = b"10.0.0.1\n\xff\n10.0.0.2\r\n";
+        let read = |args: &[&str]| {
+            let options = Options::parse(args.iter().map(|a| a.to_string())).unwrap();
+            let mut set = CidrSet::new();
+            let result = read_input(
+                "in",
+                input,
+                &mut Target::Set(&mut set),
+                &mut vec![],
+                &options,
+            );
+            (result, set.len())
+        };
+
+        assert_eq!(read(&[]), (Err("in:2: invalid UTF-8".to_string()), 1));
+        assert_eq!(read(&["--skip-invalid"]), (Ok(2), 2));
     }
 
     #[test]
-    fn cidr_parse() {
-        assert_eq!(Cidr::parse("1.2.3.4/8").to_pretty_string(), "1.0.0.0/8");
-        assert_eq!(Cidr::parse("42.43.44.45/24").to_pretty_string(), "42.43.44.0/24");
+    fn read_labelled() {
+        let options = Options::parse(std::iter::once("--labels".to_string())).unwrap();
+        let input = "10.0.0.0/25 a\n10.0.0.128/25 a\n10.0.1.0/25 a\n10.0.1.128/25 b\n\
+                     10.0.3.1 7\n10.0.2.0 255.255.255.0\n";
+        let mut map = PrefixMap::new();
+        let mut target = Target::Labels(&mut map);
+        let result = read_input("acl", input.as_bytes(), &mut target, &mut vec![], &options);
+        assert_eq!(
+            result,
+            Err("acl:6: \"10.0.2.0 255.255.255.0\": missing label".to_string())
+        );
+
+        // Only CIDRs with the same label are joined, and a number after a
+        // bare host is a label
+        let entries = |map: &PrefixMap<String>| {
+            map.iter()
+                .map(|(c, label)| format!("{} {}", c.to_pretty_string(), label))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            entries(&map),
+            [
+                "10.0.0.0/24 a",
+                "10.0.1.0/25 a",
+                "10.0.1.128/25 b",
+                "10.0.3.1/32 7"
+            ]
+        );
+
+        let cidr = Cidr::parse("10.0.1.0/24").unwrap();
+        assert_eq!(insert_labelled(&mut map, &cidr, "b"), ["10.0.1.0/25 a"]);
+        assert!(insert_labelled(&mut map, &cidr, "b").is_empty());
         assert_eq!(
-            Cidr::parse("255.255.255.255/32").to_pretty_string(),
-            "255.255.255.255/32"
+            insert_labelled(&mut map, &Cidr::parse("10.0.0.0/16").unwrap(), "c"),
+            ["10.0.0.0/24 a", "10.0.1.0/24 b", "10.0.3.1/32 7"]
         );
+        assert_eq!(entries(&map), ["10.0.0.0/16 c"]);
     }
 }
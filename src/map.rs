@@ -0,0 +1,227 @@
+use std::iter::{Chain, FromIterator};
+
+use crate::cidr::{Cidr, Family};
+use crate::tree::{self, Tree};
+
+/// A map from IPv4 and IPv6 addresses to values, stored as the smallest list
+/// of CIDRs with a value each.
+///
+/// Inserting a CIDR gives all of its addresses the new value, splitting a
+/// wider CIDR with another value around it. Two complete siblings are only
+/// joined into their parent when their values are equal, so a `CidrSet` is a
+/// `PrefixMap<()>`.
+#[derive(Clone, Debug)]
+pub struct PrefixMap<V> {
+    // IPv4 and IPv6 live in separate tries, iterated in that order
+    pub(crate) trees: [Tree<V>; 2],
+}
+
+impl<V: Clone + PartialEq> PrefixMap<V> {
+    pub fn new() -> Self {
+        PrefixMap {
+            trees: [Tree::new(Family::V4), Tree::new(Family::V6)],
+        }
+    }
+
+    pub(crate) fn tree_mut(&mut self, family: Family) -> &mut Tree<V> {
+        match family {
+            Family::V4 => &mut self.trees[0],
+            Family::V6 => &mut self.trees[1],
+        }
+    }
+
+    pub(crate) fn tree(&self, family: Family) -> &Tree<V> {
+        match family {
+            Family::V4 => &self.trees[0],
+            Family::V6 => &self.trees[1],
+        }
+    }
+
+    /// Give every address in `cidr` the value `value`, replacing what it had.
+    pub fn insert(&mut self, cidr: &Cidr, value: V) {
+        self.tree_mut(cidr.family()).insert(cidr, value);
+    }
+
+    /// Remove the value of every address in `cidr`. Returns whether any
+    /// address had one.
+    pub fn remove(&mut self, cidr: &Cidr) -> bool {
+        self.tree_mut(cidr.family()).remove(cidr)
+    }
+
+    /// The merged CIDR containing all of `cidr` and its value, if any. Use
+    /// `Cidr::from` to look up a single address.
+    pub fn longest_match(&self, cidr: &Cidr) -> Option<(Cidr, &V)> {
+        self.tree(cidr.family()).longest_match(cidr)
+    }
+
+    /// The value of every address in `cidr`, if they all have the same one.
+    pub fn get(&self, cidr: &Cidr) -> Option<&V> {
+        self.longest_match(cidr).map(|(_, value)| value)
+    }
+
+    /// The merged CIDRs overlapping `cidr` and their values: the one
+    /// containing it, or all of those inside it.
+    pub fn overlapping(&self, cidr: &Cidr) -> Entries<'_, V> {
+        Entries {
+            inner: self.trees[0]
+                .overlapping(cidr)
+                .chain(self.trees[1].overlapping(cidr)),
+        }
+    }
+
+    /// The merged CIDRs and their values in address order, IPv4 before
+    /// IPv6.
+    pub fn iter(&self) -> Entries<'_, V> {
+        Entries {
+            inner: self.trees[0].iter().chain(self.trees[1].iter()),
+        }
+    }
+
+    /// Remove all CIDRs, keeping the allocated memory.
+    pub fn clear(&mut self) {
+        self.trees.iter_mut().for_each(Tree::clear);
+    }
+
+    /// Number of merged CIDRs.
+    pub fn len(&self) -> usize {
+        self.trees.iter().map(Tree::cidrs).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<V: Clone + PartialEq> Default for PrefixMap<V> {
+    fn default() -> Self {
+        PrefixMap::new()
+    }
+}
+
+impl<V: Clone + PartialEq> Extend<(Cidr, V)> for PrefixMap<V> {
+    fn extend<I: IntoIterator<Item = (Cidr, V)>>(&mut self, iter: I) {
+        iter.into_iter()
+            .for_each(|(cidr, value)| self.insert(&cidr, value));
+    }
+}
+
+impl<V: Clone + PartialEq> FromIterator<(Cidr, V)> for PrefixMap<V> {
+    fn from_iter<I: IntoIterator<Item = (Cidr, V)>>(iter: I) -> Self {
+        let mut map = PrefixMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<'a, V: Clone + PartialEq> IntoIterator for &'a PrefixMap<V> {
+    type Item = (Cidr, &'a V);
+    type IntoIter = Entries<'a, V>;
+
+    fn into_iter(self) -> Entries<'a, V> {
+        self.iter()
+    }
+}
+
+/// Iterator over the merged CIDRs of a `PrefixMap` and their values.
+#[derive(Debug)]
+pub struct Entries<'a, V> {
+    inner: Chain<tree::Iter<'a, V>, tree::Iter<'a, V>>,
+}
+
+impl<'a, V: Clone + PartialEq> Iterator for Entries<'a, V> {
+    type Item = (Cidr, &'a V);
+
+    fn next(&mut self) -> Option<(Cidr, &'a V)> {
+        self.inner.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrefixMap;
+    use crate::cidr::Cidr;
+
+    fn map(entries: &[(&str, &'static str)]) -> PrefixMap<&'static str> {
+        entries
+            .iter()
+            .map(|(c, v)| (Cidr::parse(c).unwrap(), *v))
+            .collect()
+    }
+
+    fn pretty(map: &PrefixMap<&'static str>) -> Vec<String> {
+        map.iter()
+            .map(|(c, v)| format!("{} {}", c.to_pretty_string(), v))
+            .collect()
+    }
+
+    #[test]
+    fn map_joins_equal_values() {
+        let map = map(&[
+            ("10.0.0.0/25", "a"),
+            ("10.0.0.128/25", "a"),
+            ("10.0.1.0/25", "a"),
+            ("10.0.1.128/25", "b"),
+        ]);
+
+        assert_eq!(
+            pretty(&map),
+            ["10.0.0.0/24 a", "10.0.1.0/25 a", "10.0.1.128/25 b"]
+        );
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn map_insert_replaces() {
+        let mut map = map(&[("10.0.0.0/24", "a"), ("2001:db8::/32", "v6")]);
+
+        // A different value inside a CIDR splits it around the new one
+        map.insert(&Cidr::parse("10.0.0.64/26").unwrap(), "b");
+        assert_eq!(
+            pretty(&map),
+            [
+                "10.0.0.0/26 a",
+                "10.0.0.64/26 b",
+                "10.0.0.128/25 a",
+                "2001:db8::/32 v6"
+            ]
+        );
+
+        // Setting it back joins everything again, and a wider CIDR replaces
+        // all values below it
+        map.insert(&Cidr::parse("10.0.0.64/26").unwrap(), "a");
+        assert_eq!(pretty(&map), ["10.0.0.0/24 a", "2001:db8::/32 v6"]);
+        map.insert(&Cidr::parse("10.0.0.0/16").unwrap(), "c");
+        assert_eq!(pretty(&map), ["10.0.0.0/16 c", "2001:db8::/32 v6"]);
+    }
+
+    #[test]
+    fn map_lookup() {
+        let mut map = map(&[("0.0.0.0/0", "default"), ("198.51.100.0/24", "office")]);
+        let address = |a: &str| Cidr::parse(a).unwrap();
+
+        assert_eq!(map.get(&address("198.51.100.23")), Some(&"office"));
+        assert_eq!(map.get(&address("192.0.2.1")), Some(&"default"));
+        assert_eq!(
+            map.longest_match(&address("198.51.100.23")),
+            Some((Cidr::parse("198.51.100.0/24").unwrap(), &"office"))
+        );
+        // Addresses with different values have no single match
+        assert_eq!(map.get(&Cidr::parse("198.51.0.0/16").unwrap()), None);
+        assert_eq!(map.get(&address("2001:db8::1")), None);
+
+        let overlapping = |c: &str| {
+            map.overlapping(&Cidr::parse(c).unwrap())
+                .map(|(c, v)| format!("{} {}", c.to_pretty_string(), v))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(overlapping("198.51.100.0/25"), ["198.51.100.0/24 office"]);
+        assert_eq!(overlapping("198.51.100.7"), ["198.51.100.0/24 office"]);
+        assert_eq!(overlapping("192.0.2.0/24"), ["192.0.0.0/6 default"]);
+        assert_eq!(overlapping("198.51.0.0/16").len(), 9);
+        assert!(overlapping("2001:db8::/32").is_empty());
+
+        assert!(map.remove(&Cidr::parse("198.51.100.0/24").unwrap()));
+        assert_eq!(map.get(&address("198.51.100.23")), None);
+        assert_eq!(map.get(&address("192.0.2.1")), Some(&"default"));
+    }
+}
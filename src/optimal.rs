@@ -0,0 +1,182 @@
+//! Approximation with the fewest extra addresses, by dynamic programming over
+//! the trie.
+//!
+//! For every node and budget `k`, the table holds the fewest addresses that
+//! were not in the input but must be covered to cover the present CIDRs
+//! below the node with at most `k` CIDRs. A node either becomes a single
+//! CIDR itself, or splits its budget between its children. CIDRs skipped
+//! over by a compressed edge never need to be considered: they cover the
+//! same input addresses as the child below them, and more extra ones.
+
+use crate::cidr::Cidr;
+use crate::tree::{NodeId, Tree, ROOT};
+
+/// Cost of a budget that cannot cover the node at all.
+pub(crate) const INFINITE: u128 = u128::MAX;
+
+/// The cost tables of every node in a tree.
+pub(crate) struct Plan<'a> {
+    tree: &'a Tree,
+    tables: Vec<Vec<u128>>,
+    covered: Vec<u128>,
+}
+
+impl<'a> Plan<'a> {
+    /// Compute the tables for budgets up to `max_cidrs`.
+    pub fn new(tree: &'a Tree, max_cidrs: usize) -> Self {
+        let mut plan = Plan {
+            tree,
+            tables: vec![vec![]; tree.capacity()],
+            covered: vec![0; tree.capacity()],
+        };
+
+        // Children come after their parent in pre-order, so walking it
+        // backwards visits every child before its parent
+        for id in tree.pre_order().into_iter().rev() {
+            let node = tree.node(id);
+            if node.present() {
+                plan.covered[id as usize] = node.cidr.count();
+                plan.tables[id as usize] = vec![INFINITE, 0];
+                continue;
+            }
+
+            let mut table = vec![0];
+            let mut covered = 0u128;
+            for child in node.childs() {
+                table = combine(&table, &plan.tables[child as usize], max_cidrs);
+                covered = covered.saturating_add(plan.covered[child as usize]);
+            }
+            if covered > 0 {
+                let me = plan.cover_cost(id, covered);
+                if table.len() < 2 && max_cidrs > 0 {
+                    table.push(me);
+                }
+                for k in 1..table.len() {
+                    table[k] = table[k].min(me).min(table[k - 1]);
+                }
+            }
+            plan.covered[id as usize] = covered;
+            plan.tables[id as usize] = table;
+        }
+        plan
+    }
+
+    /// Extra addresses when the node itself is used as the only CIDR, or
+    /// `INFINITE` if the tree does not allow it.
+    fn cover_cost(&self, id: NodeId, covered: u128) -> u128 {
+        let cidr = &self.tree.node(id).cidr;
+        if !self.tree.allows(cidr) {
+            INFINITE
+        } else {
+            cidr.count().saturating_sub(covered)
+        }
+    }
+
+    /// The fewest extra addresses with at most `k` CIDRs in the whole tree,
+    /// or `INFINITE` if the tree allows no way to get down to `k`.
+    pub fn cost(&self, k: usize) -> u128 {
+        lookup(&self.tables[ROOT as usize], k)
+    }
+
+    /// The CIDRs to insert into the tree to reach `cost(k)`.
+    pub fn choose(&self, k: usize) -> Vec<Cidr> {
+        let mut chosen = vec![];
+        let mut stack = vec![(ROOT, k)];
+        while let Some((id, k)) = stack.pop() {
+            let node = self.tree.node(id);
+            let target = lookup(&self.tables[id as usize], k);
+            let covered = self.covered[id as usize];
+            if node.present() || covered == 0 {
+                continue;
+            }
+            if k >= 1 && self.cover_cost(id, covered) == target {
+                chosen.push(node.cidr);
+                continue;
+            }
+
+            match (node.left, node.right) {
+                (Some(left), Some(right)) => {
+                    let (l, r) = (&self.tables[left as usize], &self.tables[right as usize]);
+                    let a = (0..=k)
+                        .find(|&a| lookup(l, a).saturating_add(lookup(r, k - a)) == target)
+                        .unwrap();
+                    stack.push((left, a));
+                    stack.push((right, k - a));
+                }
+                (Some(child), None) | (None, Some(child)) => stack.push((child, k)),
+                (None, None) => {}
+            }
+        }
+        chosen
+    }
+}
+
+/// The cost of at most `k` CIDRs in a table.
+pub(crate) fn lookup(table: &[u128], k: usize) -> u128 {
+    table[k.min(table.len() - 1)]
+}
+
+/// Split each budget up to `max_cidrs` between two independent subtrees in
+/// the cheapest way.
+pub(crate) fn combine(a: &[u128], b: &[u128], max_cidrs: usize) -> Vec<u128> {
+    let len = (a.len() + b.len() - 1).min(max_cidrs + 1);
+    let mut out = vec![INFINITE; len];
+    for (i, x) in a.iter().enumerate().filter(|(_, x)| **x != INFINITE) {
+        for (j, y) in b.iter().enumerate().take(len.saturating_sub(i)) {
+            out[i + j] = out[i + j].min(x.saturating_add(*y));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Plan;
+    use crate::cidr::{Cidr, Family};
+    use crate::tree::Tree;
+
+    fn tree(cidrs: &[&str]) -> Tree {
+        let mut tree = Tree::new(Family::V4);
+        cidrs
+            .iter()
+            .for_each(|c| tree.insert(&Cidr::parse(c).unwrap(), ()));
+        tree
+    }
+
+    #[test]
+    fn optimal_costs() {
+        let tree = tree(&["10.0.0.0", "10.0.0.2", "10.0.0.64/26", "10.0.1.0/24"]);
+        let plan = Plan::new(&tree, 4);
+
+        assert_eq!(plan.cost(4), 0);
+        // 10.0.0.0/30 adds two addresses
+        assert_eq!(plan.cost(3), 2);
+        assert_eq!(plan.choose(3), [Cidr::parse("10.0.0.0/30").unwrap()]);
+        // 10.0.0.0/25 adds 128 - 64 - 2 addresses
+        assert_eq!(plan.cost(2), 62);
+        assert_eq!(plan.cost(1), 512 - 256 - 64 - 2);
+        assert_eq!(plan.choose(1), [Cidr::parse("10.0.0.0/23").unwrap()]);
+    }
+
+    #[test]
+    fn optimal_beats_greedy() {
+        // Greedy first widens the /30 to a /29 for 4 extra addresses, which
+        // does not save a CIDR, and then still needs 10.0.0.32/27 for 14
+        // more; optimal only takes the /27
+        let cidrs = ["10.0.0.0/30", "10.0.0.32/28", "10.0.0.56/31"];
+        let plan_tree = tree(&cidrs);
+        let plan = Plan::new(&plan_tree, 2);
+
+        let mut greedy = tree(&cidrs);
+        let before: u128 = greedy.iter().map(|(c, _)| c.count()).sum();
+        while greedy.cidrs() > 2 {
+            let best = greedy.best_coverage().unwrap().2;
+            greedy.insert(&best, ());
+        }
+        let greedy_cost = greedy.iter().map(|(c, _)| c.count()).sum::<u128>() - before;
+
+        assert_eq!(greedy_cost, 18);
+        assert_eq!(plan.cost(2), 14);
+        assert_eq!(plan.choose(2), [Cidr::parse("10.0.0.32/27").unwrap()]);
+    }
+}
@@ -0,0 +1,737 @@
+use std::cmp::Ordering::{Greater, Less};
+use std::cmp::Reverse;
+use std::iter::FromIterator;
+
+use crate::cidr::{Cidr, Family, Range};
+use crate::map::{Entries, PrefixMap};
+use crate::optimal::{Plan, INFINITE};
+use crate::tree::{score, Tree};
+
+/// A set of IPv4 and IPv6 addresses, stored as the smallest list of CIDRs
+/// covering them.
+///
+/// Inserting is lossless: CIDRs inside an existing one are dropped and two
+/// complete siblings are joined into their parent. Only `approximate` ever
+/// covers addresses that were not inserted.
+#[derive(Clone, Debug)]
+pub struct CidrSet {
+    map: PrefixMap<()>,
+}
+
+impl CidrSet {
+    pub fn new() -> Self {
+        CidrSet {
+            map: PrefixMap::new(),
+        }
+    }
+
+    fn tree_mut(&mut self, family: Family) -> &mut Tree {
+        self.map.tree_mut(family)
+    }
+
+    fn tree(&self, family: Family) -> &Tree {
+        self.map.tree(family)
+    }
+
+    pub fn insert(&mut self, cidr: &Cidr) {
+        self.map.insert(cidr, ());
+    }
+
+    /// The merged CIDRs in address order, IPv4 before IPv6.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            inner: self.map.iter(),
+        }
+    }
+
+    /// Never widen CIDRs over any address in `excluded` when approximating.
+    /// This replaces earlier exclusions, and CIDRs inserted directly may
+    /// still overlap them.
+    pub fn set_excluded(&mut self, excluded: &CidrSet) {
+        for (tree, family) in self.map.trees.iter_mut().zip(&[Family::V4, Family::V6]) {
+            tree.set_excluded(excluded.tree(*family).iter().map(|(c, _)| c).collect());
+        }
+    }
+
+    /// Never widen CIDRs of `family` to a prefix shorter than `min_size` when
+    /// approximating, nor join the CIDRs approximation adds into one. CIDRs
+    /// inserted directly are still joined as usual.
+    pub fn set_min_size(&mut self, family: Family, min_size: usize) {
+        self.tree_mut(family).set_min_size(min_size);
+    }
+
+    /// Whether every address in `cidr` is in the set.
+    pub fn contains(&self, cidr: &Cidr) -> bool {
+        self.longest_match(cidr).is_some()
+    }
+
+    /// The merged CIDR containing all of `cidr`, if any. Use `Cidr::from`
+    /// to look up a single address.
+    pub fn longest_match(&self, cidr: &Cidr) -> Option<Cidr> {
+        self.map.longest_match(cidr).map(|(cidr, _)| cidr)
+    }
+
+    /// How much of `cidr` is in the set.
+    pub fn overlap(&self, cidr: &Cidr) -> Overlap {
+        if self.contains(cidr) {
+            Overlap::Full
+        } else if self.tree(cidr.family()).addresses_within(cidr) > 0 {
+            Overlap::Partial
+        } else {
+            Overlap::Disjoint
+        }
+    }
+
+    /// Remove every address in `cidr`. A merged CIDR around it is split
+    /// into the CIDRs covering what remains. Returns whether any address was
+    /// in the set.
+    pub fn remove(&mut self, cidr: &Cidr) -> bool {
+        self.map.remove(cidr)
+    }
+
+    /// Remove all CIDRs, keeping the allocated memory to build the set
+    /// again. Exclusions and minimum sizes are kept.
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// Make room for about `additional` more CIDRs of `family` without
+    /// reallocating.
+    pub fn reserve(&mut self, family: Family, additional: usize) {
+        // A trie has at most two nodes per present CIDR
+        self.tree_mut(family).reserve(2 * additional);
+    }
+
+    /// Number of merged CIDRs.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of trie nodes used to store the set.
+    pub fn nodes(&self) -> usize {
+        self.map.trees.iter().map(Tree::nodes).sum()
+    }
+
+    /// Fraction of the address space of `family` that is covered.
+    pub fn coverage(&self, family: Family) -> f64 {
+        self.tree(family).coverage()
+    }
+
+    /// Widen CIDRs until at most `max_cidrs` remain, each step picking the
+    /// CIDR that adds the fewest addresses not already in the set.
+    pub fn approximate(&mut self, max_cidrs: usize) {
+        self.approximate_within(Some(max_cidrs), u128::MAX);
+    }
+
+    /// Like `approximate`, but also stop before the total number of
+    /// addresses added would exceed `max_extra`. Returns the exact number of
+    /// addresses added and why the approximation stopped.
+    ///
+    /// Without `max_cidrs` only the budget limits the approximation.
+    /// If excluded addresses or minimum sizes are in the way, this stops at
+    /// the fewest CIDRs that respect them. Widenings made after the count
+    /// last fell are undone, as they added addresses without saving a CIDR.
+    pub fn approximate_within(
+        &mut self,
+        max_cidrs: Option<usize>,
+        max_extra: u128,
+    ) -> (u128, Stop) {
+        let max_cidrs = max_cidrs.unwrap_or(0);
+        let mut extra = 0u128;
+        // Steps since the count last fell, with the CIDRs each replaced
+        let mut pending: Vec<(Cidr, Vec<Cidr>, u128)> = vec![];
+        let mut fewest = self.len();
+        let mut stop = Stop::Reached;
+        while self.len() > max_cidrs {
+            // Pick the cheapest candidate across both address families
+            let best = self
+                .map
+                .trees
+                .iter()
+                .filter_map(Tree::best_coverage)
+                .min_by(|a, b| if score(a) < score(b) { Less } else { Greater })
+                .map(|(_, _, cidr)| *cidr);
+
+            let cidr = match best {
+                Some(cidr) => cidr,
+                None => {
+                    stop = Stop::Blocked;
+                    break;
+                }
+            };
+            let cost = cidr.count() - self.tree(cidr.family()).addresses_within(&cidr);
+            if extra.saturating_add(cost) > max_extra {
+                stop = Stop::Budget;
+                break;
+            }
+            let replaced = self
+                .tree(cidr.family())
+                .overlapping(&cidr)
+                .map(|(c, _)| c)
+                .collect();
+            extra += cost;
+            self.tree_mut(cidr.family()).widen(&cidr, ());
+            if self.len() < fewest {
+                fewest = self.len();
+                pending.clear();
+            } else {
+                pending.push((cidr, replaced, cost));
+            }
+        }
+
+        for (cidr, replaced, cost) in pending.into_iter().rev() {
+            self.remove(&cidr);
+            let tree = self.tree_mut(cidr.family());
+            replaced.iter().for_each(|c| tree.widen(c, ()));
+            extra -= cost;
+        }
+        (extra, stop)
+    }
+
+    /// Widen CIDRs until at most `max_cidrs` remain, covering the fewest
+    /// possible addresses that are not already in the set. IPv4 and IPv6
+    /// each keep at least one CIDR if they have any.
+    ///
+    /// This is exact where `approximate` is greedy, at the price of time
+    /// and memory proportional to the number of CIDRs times `max_cidrs`.
+    pub fn approximate_optimal(&mut self, max_cidrs: usize) {
+        self.approximate_optimal_within(Some(max_cidrs), u128::MAX);
+    }
+
+    /// Like `approximate_optimal`, but keep as few CIDRs as possible, no
+    /// fewer than `max_cidrs`, without adding more than `max_extra`
+    /// addresses. Returns the exact number of addresses added and why the
+    /// approximation stopped. Exclusions and minimum sizes are respected
+    /// even if that takes more than `max_cidrs`.
+    /// Without `max_cidrs` only the budget limits the approximation.
+    ///
+    /// Unless `max_cidrs` fits the budget and the limits, this needs the
+    /// full cost tables, which take time quadratic in the number of CIDRs in
+    /// the worst case.
+    pub fn approximate_optimal_within(
+        &mut self,
+        max_cidrs: Option<usize>,
+        max_extra: u128,
+    ) -> (u128, Stop) {
+        let families = self.map.trees.iter().filter(|t| t.cidrs() > 0).count();
+        let target = max_cidrs.unwrap_or(0);
+        let max_cidrs = target.max(families);
+        // Keeping a CIDR per family is as much a limit as the exclusions
+        let reached = if max_cidrs == target {
+            Stop::Reached
+        } else {
+            Stop::Blocked
+        };
+        let len = self.len();
+        if len <= max_cidrs {
+            return (
+                0,
+                if len <= target {
+                    Stop::Reached
+                } else {
+                    reached
+                },
+            );
+        }
+
+        let (chosen, extra, stop) = {
+            let [v4, v6] = &self.map.trees;
+            let fits = |cost: u128| cost != INFINITE && cost <= max_extra;
+            // Split a budget of k CIDRs between the families in the cheapest
+            // way
+            let split = |plan4: &Plan, plan6: &Plan, k: usize| {
+                (0..=k)
+                    .map(|k4| (plan4.cost(k4).saturating_add(plan6.cost(k - k4)), k4))
+                    .min()
+                    .unwrap()
+            };
+
+            let mut plans = (Plan::new(v4, max_cidrs), Plan::new(v6, max_cidrs));
+            let mut k = max_cidrs;
+            let mut stop = reached;
+            let cost = split(&plans.0, &plans.1, k).0;
+            if !fits(cost) {
+                stop = if cost == INFINITE {
+                    Stop::Blocked
+                } else {
+                    Stop::Budget
+                };
+                // The cost only falls with more CIDRs and is zero for all of
+                // them, so search the full tables for the fewest that fit
+                plans = (Plan::new(v4, len), Plan::new(v6, len));
+                let (mut lo, mut hi) = (max_cidrs, len);
+                while hi - lo > 1 {
+                    let mid = lo + (hi - lo) / 2;
+                    if fits(split(&plans.0, &plans.1, mid).0) {
+                        hi = mid;
+                    } else {
+                        lo = mid;
+                    }
+                }
+                k = hi;
+            }
+
+            let (extra, k4) = split(&plans.0, &plans.1, k);
+            let mut chosen = plans.0.choose(k4);
+            chosen.extend(plans.1.choose(k - k4));
+            (chosen, extra, stop)
+        };
+        chosen
+            .iter()
+            .for_each(|cidr| self.tree_mut(cidr.family()).widen(cidr, ()));
+        (extra, stop)
+    }
+
+    /// The addresses in either set.
+    pub fn union(&self, other: &CidrSet) -> CidrSet {
+        let mut set = self.clone();
+        set.extend(other);
+        set
+    }
+
+    /// The addresses in this set but not in `other`.
+    pub fn difference(&self, other: &CidrSet) -> CidrSet {
+        let mut set = self.clone();
+        for cidr in other {
+            set.remove(&cidr);
+        }
+        set
+    }
+
+    /// The addresses in both sets.
+    pub fn intersection(&self, other: &CidrSet) -> CidrSet {
+        self.difference(&self.difference(other))
+    }
+
+    /// The addresses in exactly one of the sets.
+    pub fn symmetric_difference(&self, other: &CidrSet) -> CidrSet {
+        self.difference(other).union(&other.difference(self))
+    }
+
+    /// The addresses in `universe` but not in this set.
+    pub fn complement(&self, universe: &CidrSet) -> CidrSet {
+        universe.difference(self)
+    }
+
+    /// Drop all but the `max_cidrs` CIDRs with the most addresses, so the set
+    /// only ever shrinks. Returns the dropped CIDRs in address order.
+    pub fn retain_largest(&mut self, max_cidrs: usize) -> Vec<Cidr> {
+        self.retain_largest_by_key(max_cidrs, Cidr::count)
+    }
+
+    /// Drop all but the `max_cidrs` CIDRs with the largest `weight`, keeping
+    /// the first in address order on ties. Returns the dropped CIDRs in
+    /// address order.
+    pub fn retain_largest_by_key<K, F>(&mut self, max_cidrs: usize, mut weight: F) -> Vec<Cidr>
+    where
+        K: Ord,
+        F: FnMut(&Cidr) -> K,
+    {
+        let mut cidrs = self.iter().collect::<Vec<_>>();
+        if cidrs.len() <= max_cidrs {
+            return vec![];
+        }
+
+        // The sort is stable, so ties stay in address order
+        cidrs.sort_by_key(|cidr| Reverse(weight(cidr)));
+        let mut dropped = cidrs.split_off(max_cidrs);
+        dropped.sort();
+        dropped.iter().for_each(|cidr| {
+            self.remove(cidr);
+        });
+        dropped
+    }
+
+    /// Exact number of addresses of `family` in the set, saturating at
+    /// `u128::MAX` for all of IPv6.
+    pub fn addresses(&self, family: Family) -> u128 {
+        self.tree(family).addresses()
+    }
+
+    /// The covered addresses as contiguous ranges, joining neighbouring
+    /// CIDRs even when they do not form a single CIDR.
+    pub fn ranges(&self) -> Vec<Range> {
+        let mut ranges: Vec<Range> = vec![];
+        for cidr in self.iter() {
+            match ranges.last_mut() {
+                Some(r)
+                    if r.family == cidr.family() && r.last.checked_add(1) == Some(cidr.first()) =>
+                {
+                    r.last = cidr.last()
+                }
+                _ => ranges.push(Range {
+                    family: cidr.family(),
+                    first: cidr.first(),
+                    last: cidr.last(),
+                }),
+            }
+        }
+        ranges
+    }
+}
+
+impl Default for CidrSet {
+    fn default() -> Self {
+        CidrSet::new()
+    }
+}
+
+impl Extend<Cidr> for CidrSet {
+    fn extend<I: IntoIterator<Item = Cidr>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|cidr| self.insert(&cidr));
+    }
+}
+
+impl FromIterator<Cidr> for CidrSet {
+    fn from_iter<I: IntoIterator<Item = Cidr>>(iter: I) -> Self {
+        let mut set = CidrSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<'a> IntoIterator for &'a CidrSet {
+    type Item = Cidr;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// How much of a CIDR is in a `CidrSet`, from `CidrSet::overlap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overlap {
+    /// Every address is in the set.
+    Full,
+    /// Some addresses are in the set and some are not.
+    Partial,
+    /// No address is in the set.
+    Disjoint,
+}
+
+/// Why an approximation stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// At most the requested number of CIDRs remain.
+    Reached,
+    /// Any further widening would exceed the budget of extra addresses.
+    Budget,
+    /// No widening allowed by the exclusions and minimum sizes lowers the
+    /// count any further, nor can IPv4 and IPv6 be joined.
+    Blocked,
+}
+
+/// Iterator over the merged CIDRs of a `CidrSet`.
+#[derive(Debug)]
+pub struct Iter<'a> {
+    inner: Entries<'a, ()>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Cidr;
+
+    fn next(&mut self) -> Option<Cidr> {
+        self.inner.next().map(|(cidr, _)| cidr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CidrSet, Overlap, Stop};
+    use crate::cidr::{Cidr, Family, Range};
+
+    fn set(cidrs: &[&str]) -> CidrSet {
+        cidrs.iter().map(|c| Cidr::parse(c).unwrap()).collect()
+    }
+
+    fn pretty(set: &CidrSet) -> Vec<String> {
+        set.iter().map(|c| c.to_pretty_string()).collect()
+    }
+
+    #[test]
+    fn set_iter_order() {
+        let set = set(&["2001:db8::/32", "10.0.1.0/24", "10.0.0.0/24", "1.2.3.4"]);
+
+        assert_eq!(set.len(), 3);
+        assert_eq!(pretty(&set), ["1.2.3.4/32", "10.0.0.0/23", "2001:db8::/32"]);
+    }
+
+    #[test]
+    fn set_approximate() {
+        // 64 scattered /32s plus two sibling /25s that collapse into a /24
+        let mut set: CidrSet = (0..64)
+            .map(|i| Cidr::parse(&format!("10.{}.0.1/32", i * 2)).unwrap())
+            .chain(vec![
+                Cidr::parse("192.0.2.0/25").unwrap(),
+                Cidr::parse("192.0.2.128/25").unwrap(),
+            ])
+            .collect();
+        let coverage = set.coverage(Family::V4);
+        assert_eq!(set.len(), 65);
+
+        set.approximate(40);
+        assert_eq!(set.len(), 40);
+        assert!(set.coverage(Family::V4) > coverage);
+    }
+
+    #[test]
+    fn set_approximate_within() {
+        let cidrs = ["10.0.0.0/30", "10.0.0.32/28", "10.0.0.56/31"];
+
+        // Greedy widens the /30 and /31 to /29s for 10 without saving a
+        // CIDR, cannot afford the 8 more for 10.0.0.32/27, and so undoes
+        // those widenings again
+        let mut greedy = set(&cidrs);
+        assert_eq!(greedy.approximate_within(None, 16), (0, Stop::Budget));
+        assert_eq!(pretty(&greedy), cidrs);
+        assert_eq!(greedy.approximate_within(None, 18), (18, Stop::Budget));
+        assert_eq!(pretty(&greedy), ["10.0.0.0/29", "10.0.0.32/27"]);
+
+        let mut optimal = set(&cidrs);
+        assert_eq!(
+            optimal.approximate_optimal_within(None, 16),
+            (14, Stop::Budget)
+        );
+        assert_eq!(pretty(&optimal), ["10.0.0.0/30", "10.0.0.32/27"]);
+
+        // The count limit stops the approximation before the budget does
+        let mut limited = set(&cidrs);
+        assert_eq!(
+            limited.approximate_optimal_within(Some(3), 16),
+            (0, Stop::Reached)
+        );
+        assert_eq!(limited.len(), 3);
+        assert_eq!(limited.addresses(Family::V4), 22);
+    }
+
+    #[test]
+    fn set_excluded() {
+        let excluded = set(&["10.0.0.16/28"]);
+        let cidrs = ["10.0.0.0", "10.0.0.32", "10.0.0.40", "10.0.1.0/24"];
+
+        // Anything wider than 10.0.0.0/28 or 10.0.0.32/27 reaches into
+        // 10.0.0.16/28, so three CIDRs is the best either can do. Greedy
+        // widens 10.0.0.0 to a /30 before joining the hosts at 10.0.0.32,
+        // and undoes everything it widened after that
+        let mut greedy = set(&cidrs);
+        greedy.set_excluded(&excluded);
+        assert_eq!(
+            greedy.approximate_within(Some(1), u128::MAX),
+            (3 + 14, Stop::Blocked)
+        );
+        assert_eq!(
+            pretty(&greedy),
+            ["10.0.0.0/30", "10.0.0.32/28", "10.0.1.0/24"]
+        );
+
+        // A budget that is never reached does not change why they stop
+        let mut budget = set(&cidrs);
+        budget.set_excluded(&excluded);
+        assert_eq!(budget.approximate_within(Some(1), 1000).1, Stop::Blocked);
+        let mut budget = set(&cidrs);
+        budget.set_excluded(&excluded);
+        assert_eq!(
+            budget.approximate_optimal_within(Some(1), 1000).1,
+            Stop::Blocked
+        );
+
+        let mut optimal = set(&cidrs);
+        optimal.set_excluded(&excluded);
+        assert_eq!(
+            optimal.approximate_optimal_within(Some(1), u128::MAX),
+            (14, Stop::Blocked)
+        );
+        assert_eq!(
+            pretty(&optimal),
+            ["10.0.0.0/32", "10.0.0.32/28", "10.0.1.0/24"]
+        );
+    }
+
+    #[test]
+    fn set_min_size() {
+        let cidrs = [
+            "10.0.0.1",
+            "10.1.0.1",
+            "10.2.0.1",
+            "10.3.0.1",
+            "10.0.0.0/15",
+        ];
+        let mut set = set(&cidrs[..4]);
+        set.set_min_size(Family::V4, 16);
+
+        // Widening the hosts to their /16s would not save a CIDR, as those
+        // cannot be joined any further, so the hosts stay as they are
+        assert_eq!(
+            set.approximate_within(Some(1), u128::MAX),
+            (0, Stop::Blocked)
+        );
+        assert_eq!(
+            pretty(&set),
+            ["10.0.0.1/32", "10.1.0.1/32", "10.2.0.1/32", "10.3.0.1/32"]
+        );
+
+        // Nor are the /16s approximation widens to
+        let mut pairs = self::set(&["10.0.0.1", "10.0.255.1", "10.1.0.1", "10.1.255.1"]);
+        pairs.set_min_size(Family::V4, 16);
+        pairs.approximate(2);
+        assert_eq!(pretty(&pairs), ["10.0.0.0/16", "10.1.0.0/16"]);
+
+        // CIDRs inserted directly are joined whether or not they were
+        // inserted before the cap
+        set.insert(&Cidr::parse(cidrs[4]).unwrap());
+        assert_eq!(pretty(&set), ["10.0.0.0/15", "10.2.0.1/32", "10.3.0.1/32"]);
+        set.insert(&Cidr::parse("10.2.0.0/16").unwrap());
+        set.insert(&Cidr::parse("10.3.0.0/16").unwrap());
+        assert_eq!(pretty(&set), ["10.0.0.0/14"]);
+    }
+
+    #[test]
+    fn set_retain_largest() {
+        let cidrs = [
+            "10.0.0.1",
+            "10.0.0.8/29",
+            "10.0.1.0/24",
+            "10.0.2.0/29",
+            "10.0.3.7",
+        ];
+        let mut set = set(&cidrs);
+
+        // The /29s tie, and the first in address order stays
+        let dropped = set.retain_largest(2);
+        assert_eq!(pretty(&set), ["10.0.0.8/29", "10.0.1.0/24"]);
+        let dropped = dropped
+            .iter()
+            .map(|c| c.to_pretty_string())
+            .collect::<Vec<_>>();
+        assert_eq!(dropped, ["10.0.0.1/32", "10.0.2.0/29", "10.0.3.7/32"]);
+
+        let mut set = self::set(&cidrs);
+        let dropped = set.retain_largest_by_key(1, |c| c.first());
+        assert_eq!(pretty(&set), ["10.0.3.7/32"]);
+        assert_eq!(dropped.len(), 4);
+        assert!(set.retain_largest(1).is_empty());
+    }
+
+    #[test]
+    fn set_operations() {
+        let a = set(&["10.0.0.0/24", "10.0.2.0/24", "2001:db8::/46"]);
+        let b = set(&["10.0.0.64/26", "10.0.1.0/24", "2001:db8:1::/48"]);
+
+        assert_eq!(
+            pretty(&a.union(&b)),
+            ["10.0.0.0/23", "10.0.2.0/24", "2001:db8::/46"]
+        );
+        assert_eq!(
+            pretty(&a.intersection(&b)),
+            ["10.0.0.64/26", "2001:db8:1::/48"]
+        );
+        // Removing part of a present CIDR leaves the halves around it
+        assert_eq!(
+            pretty(&a.difference(&b)),
+            [
+                "10.0.0.0/26",
+                "10.0.0.128/25",
+                "10.0.2.0/24",
+                "2001:db8::/48",
+                "2001:db8:2::/47"
+            ]
+        );
+        assert_eq!(
+            pretty(
+                &a.symmetric_difference(&b)
+                    .intersection(&set(&["10.0.0.0/8"]))
+            ),
+            ["10.0.0.0/26", "10.0.0.128/25", "10.0.1.0/24", "10.0.2.0/24"]
+        );
+        assert_eq!(
+            pretty(&set(&["128.0.0.0/2", "0.0.0.0/1"]).complement(&set(&["0.0.0.0/0"]))),
+            ["192.0.0.0/2"]
+        );
+
+        // Removing everything leaves an empty tree, free of leftover nodes
+        let empty = a.difference(&set(&["0.0.0.0/0", "::/0"]));
+        assert!(empty.is_empty());
+        assert_eq!(empty.nodes(), 2);
+    }
+
+    #[test]
+    fn set_remove() {
+        let mut set = set(&["10.0.0.0/24", "10.0.1.0/24", "2001:db8::/32"]);
+
+        // Expiring one entry of a merged pair leaves the other
+        assert!(set.remove(&Cidr::parse("10.0.1.0/24").unwrap()));
+        assert_eq!(pretty(&set), ["10.0.0.0/24", "2001:db8::/32"]);
+        assert!(!set.remove(&Cidr::parse("10.0.1.0/24").unwrap()));
+
+        assert!(set.remove(&Cidr::parse("10.0.0.128").unwrap()));
+        assert_eq!(
+            pretty(&set),
+            [
+                "10.0.0.0/25",
+                "10.0.0.129/32",
+                "10.0.0.130/31",
+                "10.0.0.132/30",
+                "10.0.0.136/29",
+                "10.0.0.144/28",
+                "10.0.0.160/27",
+                "10.0.0.192/26",
+                "2001:db8::/32"
+            ]
+        );
+        assert_eq!(set.addresses(Family::V4), 255);
+
+        // Putting it back joins everything again
+        set.insert(&Cidr::parse("10.0.0.128").unwrap());
+        assert_eq!(pretty(&set), ["10.0.0.0/24", "2001:db8::/32"]);
+    }
+
+    #[test]
+    fn set_queries() {
+        let set = set(&["198.51.100.0/24", "10.1.128.0/17", "2001:db8::/32"]);
+        let cidr = |c: &str| Cidr::parse(c).unwrap();
+
+        let address = Cidr::from("198.51.100.23".parse::<std::net::IpAddr>().unwrap());
+        assert!(set.contains(&address));
+        assert_eq!(set.longest_match(&address), Some(cidr("198.51.100.0/24")));
+        assert_eq!(
+            set.longest_match(&cidr("2001:db8:1::/48")),
+            Some(cidr("2001:db8::/32"))
+        );
+        assert_eq!(set.longest_match(&cidr("198.51.100.0/23")), None);
+        assert!(!set.contains(&cidr("198.51.101.1")));
+
+        assert_eq!(set.overlap(&cidr("10.1.200.0/24")), Overlap::Full);
+        assert_eq!(set.overlap(&cidr("10.1.0.0/16")), Overlap::Partial);
+        assert_eq!(set.overlap(&cidr("0.0.0.0/0")), Overlap::Partial);
+        assert_eq!(set.overlap(&cidr("10.1.0.0/17")), Overlap::Disjoint);
+        assert_eq!(set.overlap(&cidr("2001:db9::/32")), Overlap::Disjoint);
+    }
+
+    #[test]
+    fn set_clear() {
+        let mut set = set(&["10.0.0.0/24", "2001:db8::/32"]);
+        set.clear();
+        assert!(set.is_empty());
+        assert_eq!(set.iter().count(), 0);
+
+        set.insert(&Cidr::parse("192.0.2.0/24").unwrap());
+        assert_eq!(pretty(&set), ["192.0.2.0/24"]);
+    }
+
+    #[test]
+    fn set_ranges() {
+        let set = set(&["10.0.0.1/32", "10.0.0.2/31", "10.0.0.4/32", "10.0.0.8/32"]);
+
+        let ranges = set
+            .ranges()
+            .iter()
+            .map(Range::to_pretty_string)
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, ["10.0.0.1-10.0.0.4", "10.0.0.8-10.0.0.8"]);
+    }
+}
@@ -0,0 +1,793 @@
+use std::cmp::Ordering::{Greater, Less};
+
+use crate::cidr::{Cidr, Family};
+
+/// Number of addresses that were not in the input but would be covered if
+/// the candidate from `Tree::best_coverage` was inserted.
+pub(crate) fn score(candidate: &(f64, usize, Cidr)) -> f64 {
+    candidate.2.addresses() * (1.0 - candidate.0)
+}
+
+/// Index of a node in the arena of its `Tree`.
+pub(crate) type NodeId = u32;
+
+/// The root is always the first node in the arena.
+pub(crate) const ROOT: NodeId = 0;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Node<V> {
+    pub value: Option<V>,
+    pub node_count: usize,
+    pub cidr_count: usize,
+    pub addresses: u128,
+    pub coverage: f64,
+    pub cidr: Cidr,
+    pub left: Option<NodeId>,
+    pub right: Option<NodeId>,
+    pub best_coverage: Option<(f64, usize, Cidr)>,
+}
+
+impl<V> Node<V> {
+    fn new(cidr: Cidr) -> Self {
+        Node {
+            cidr,
+            value: None,
+            cidr_count: 0,
+            addresses: 0,
+            node_count: 1,
+            coverage: 0.0,
+            left: None,
+            right: None,
+            best_coverage: None,
+        }
+    }
+
+    /// Whether the node is one of the merged CIDRs, with a value.
+    pub fn present(&self) -> bool {
+        self.value.is_some()
+    }
+
+    pub fn childs(&self) -> impl Iterator<Item = NodeId> {
+        self.left.into_iter().chain(self.right)
+    }
+}
+
+/// A path-compressed binary trie over address bits with the merged CIDRs as
+/// present nodes, each carrying a value. Two halves are only joined into
+/// one CIDR when their values are equal, so a set is a `Tree<()>`.
+///
+/// Nodes only exist where a CIDR is present or where two subtrees branch, so
+/// a child can be many bits below its parent. The root is always the /0.
+///
+/// The nodes live in one arena and link to each other by index. Nodes cut
+/// off by a wider CIDR are put on a free list and reused by later inserts,
+/// and all walks over the trie are iterative.
+///
+/// CIDRs overlapping the excluded ones or shorter than `min_size` are never
+/// candidates for `best_coverage`, so approximation cannot cover excluded
+/// addresses or produce such wide CIDRs.
+#[derive(Clone, Debug)]
+pub(crate) struct Tree<V = ()> {
+    nodes: Vec<Node<V>>,
+    free: Vec<NodeId>,
+    // Sorted and disjoint
+    excluded: Vec<Cidr>,
+    min_size: usize,
+    // Halves are not joined into a prefix shorter than this, set by `widen`
+    min_join: usize,
+    // Scratch space for the path walked by `insert`, kept to reuse its
+    // allocation
+    path: Vec<NodeId>,
+}
+
+impl<V: Clone + PartialEq> Tree<V> {
+    pub fn new(family: Family) -> Self {
+        Tree {
+            nodes: vec![Node::new(Cidr::root(family))],
+            free: vec![],
+            excluded: vec![],
+            min_size: 0,
+            min_join: 0,
+            path: vec![],
+        }
+    }
+
+    /// Replace the excluded CIDRs, which must be sorted and disjoint.
+    pub fn set_excluded(&mut self, excluded: Vec<Cidr>) {
+        self.excluded = excluded;
+        self.update_all_best_coverage();
+    }
+
+    /// Never offer candidates with a prefix shorter than `min_size`, nor
+    /// let `widen` join complete halves into one.
+    pub fn set_min_size(&mut self, min_size: usize) {
+        self.min_size = min_size;
+        self.update_all_best_coverage();
+    }
+
+    /// Whether approximation may insert `cidr`.
+    pub fn allows(&self, cidr: &Cidr) -> bool {
+        cidr.size() >= self.min_size && !self.is_excluded(cidr)
+    }
+
+    /// Whether `cidr` shares any address with an excluded CIDR.
+    pub fn is_excluded(&self, cidr: &Cidr) -> bool {
+        // The last excluded CIDR starting at or before the end of `cidr` is
+        // the only one that can reach into it
+        let i = self.excluded.partition_point(|e| e.first() <= cidr.last());
+        i > 0 && self.excluded[i - 1].last() >= cidr.first()
+    }
+
+    /// Remove all CIDRs but keep the allocated nodes for reuse. The excluded
+    /// CIDRs stay.
+    pub fn clear(&mut self) {
+        let family = self.root().cidr.family();
+        self.nodes.truncate(1);
+        self.nodes[0] = Node::new(Cidr::root(family));
+        self.free.clear();
+    }
+
+    /// Make room for at least `additional` more nodes.
+    pub fn reserve(&mut self, additional: usize) {
+        self.nodes
+            .reserve(additional.saturating_sub(self.free.len()));
+    }
+
+    /// Number of slots in the arena, used or not. Every `NodeId` is below
+    /// this.
+    pub fn capacity(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The nodes in use, each before its children.
+    pub fn pre_order(&self) -> Vec<NodeId> {
+        let mut order = vec![];
+        let mut stack = vec![ROOT];
+        while let Some(id) = stack.pop() {
+            order.push(id);
+            stack.extend(self.node(id).childs());
+        }
+        order
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node<V> {
+        &self.nodes[id as usize]
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node<V> {
+        &mut self.nodes[id as usize]
+    }
+
+    pub fn root(&self) -> &Node<V> {
+        self.node(ROOT)
+    }
+
+    fn alloc(&mut self, cidr: Cidr) -> NodeId {
+        match self.free.pop() {
+            Some(id) => {
+                *self.node_mut(id) = Node::new(cidr);
+                id
+            }
+            None => {
+                self.nodes.push(Node::new(cidr));
+                (self.nodes.len() - 1) as NodeId
+            }
+        }
+    }
+
+    /// Put every node below `id` on the free list.
+    fn free_childs(&mut self, id: NodeId) {
+        let mut stack: Vec<NodeId> = self.node(id).childs().collect();
+        while let Some(child) = stack.pop() {
+            stack.extend(self.node(child).childs());
+            self.free.push(child);
+        }
+    }
+
+    fn make_present(&mut self, id: NodeId, value: V) {
+        // Remove any children as this new CIDR has full coverage anyway
+        self.free_childs(id);
+
+        let node = self.node_mut(id);
+        node.value = Some(value);
+        node.left = None;
+        node.right = None;
+    }
+
+    fn optimize(&mut self, id: NodeId) {
+        // Only children right below this node are halves of it
+        let node = self.node(id);
+        let size = node.cidr.size() + 1;
+        let half = |o: Option<NodeId>| {
+            o.map(|c| self.node(c))
+                .filter(|t| t.cidr.size() == size)
+                .and_then(|t| t.value.as_ref())
+        };
+
+        match (half(node.left), half(node.right)) {
+            (Some(left), Some(right)) if left == right && size > self.min_join => {
+                // Replace childs
+                let value = left.clone();
+                self.make_present(id, value)
+            }
+            _ => {}
+        }
+    }
+
+    fn update_coverage(&mut self, id: NodeId) {
+        let node = self.node(id);
+        let size = node.cidr.size();
+        let childs = node
+            .childs()
+            .map(|c| self.node(c))
+            .map(|t| t.coverage / 2.0_f64.powi((t.cidr.size() - size) as i32))
+            // Not `sum`, which is -0 for no children
+            .fold(0.0, |a, b| a + b);
+        let node = self.node_mut(id);
+        node.coverage = if node.present() { 1.0 } else { childs };
+    }
+
+    fn update_node_count(&mut self, id: NodeId) {
+        let node = self.node(id);
+        let childs = node
+            .childs()
+            .map(|c| self.node(c).node_count)
+            .sum::<usize>();
+        self.node_mut(id).node_count = 1 + childs;
+    }
+
+    fn update_cidr_count(&mut self, id: NodeId) {
+        let node = self.node(id);
+        let childs = node.childs().map(|c| self.node(c).cidr_count).sum();
+        let node = self.node_mut(id);
+        node.cidr_count = if node.present() { 1 } else { childs };
+    }
+
+    fn update_addresses(&mut self, id: NodeId) {
+        let node = self.node(id);
+        let childs = node
+            .childs()
+            .map(|c| self.node(c).addresses)
+            .fold(0u128, u128::saturating_add);
+        let node = self.node_mut(id);
+        node.addresses = if node.present() {
+            node.cidr.count()
+        } else {
+            childs
+        };
+    }
+
+    fn update_best_coverage(&mut self, id: NodeId) {
+        let node = self.node(id);
+        if node.present() {
+            self.node_mut(id).best_coverage = None;
+            return;
+        }
+
+        // The CIDRs skipped over by a compressed edge are candidates too, but
+        // only the one right above the child can beat the others
+        let size = node.cidr.size();
+        let above = |t: &Node<V>| {
+            if t.cidr.size() > size + 1 && self.allows(&t.cidr.parent()) {
+                Some((t.coverage / 2.0, t.cidr_count, t.cidr.parent()))
+            } else {
+                None
+            }
+        };
+        // An empty tree has nothing to widen
+        let me = if node.cidr_count == 0 || !self.allows(&node.cidr) {
+            None
+        } else {
+            Some((node.coverage, node.cidr_count, node.cidr))
+        };
+        let left = node.left.map(|c| self.node(c));
+        let right = node.right.map(|c| self.node(c));
+        let all = [
+            me,
+            left.and_then(above),
+            left.and_then(|t| t.best_coverage),
+            right.and_then(above),
+            right.and_then(|t| t.best_coverage),
+        ];
+
+        let candidates = all.iter().flatten();
+
+        self.node_mut(id).best_coverage = candidates
+            .min_by(|a, b| if score(a) < score(b) { Less } else { Greater })
+            .cloned()
+    }
+
+    fn update_all_best_coverage(&mut self) {
+        for id in self.pre_order().into_iter().rev() {
+            self.update_best_coverage(id);
+        }
+    }
+
+    fn update(&mut self, id: NodeId) {
+        self.optimize(id);
+        self.update_cidr_count(id);
+        self.update_addresses(id);
+        self.update_node_count(id);
+        self.update_coverage(id);
+        self.update_best_coverage(id);
+    }
+
+    fn set_child(&mut self, id: NodeId, bit: bool, child: Option<NodeId>) {
+        let node = self.node_mut(id);
+        if bit {
+            node.right = child;
+        } else {
+            node.left = child;
+        }
+    }
+
+    /// Insert `cidr` as approximation does, without joining complete halves
+    /// into a prefix shorter than `min_size`.
+    pub fn widen(&mut self, cidr: &Cidr, value: V) {
+        self.min_join = self.min_size;
+        self.insert(cidr, value);
+        self.min_join = 0;
+    }
+
+    /// Give every address in `cidr` the value `value`.
+    pub fn insert(&mut self, cidr: &Cidr, value: V) {
+        let mut path = std::mem::take(&mut self.path);
+        path.clear();
+
+        let mut id = ROOT;
+        loop {
+            path.push(id);
+            let node = self.node(id);
+            let depth = node.cidr.size();
+            if let Some(old) = &node.value {
+                if *old == value {
+                    // Already covered, nothing changes
+                    self.path = path;
+                    return;
+                }
+                if depth < cidr.size() {
+                    // Carve `cidr` out of the wider CIDR, then insert it
+                    // into the hole
+                    self.split(id, cidr, &mut path);
+                    for &id in path.iter().rev() {
+                        self.update(id);
+                    }
+                    self.path = path;
+                    return self.insert(cidr, value);
+                }
+            }
+
+            if depth == cidr.size() {
+                // We traversed the full path so this node is the one we want
+                self.make_present(id, value);
+                break;
+            }
+
+            let bit = cidr.bit(depth);
+            let opt_child = if bit { node.right } else { node.left };
+            id = match opt_child {
+                Some(child) if self.node(child).cidr.contains(cidr) => child,
+                Some(child) => {
+                    // Split the edge where the paths to the child and the new
+                    // CIDR part, which may be at the new CIDR itself
+                    let old = self.node(child).cidr;
+                    let split = self.alloc(old.common(cidr));
+                    self.set_child(split, old.bit(self.node(split).cidr.size()), Some(child));
+                    self.set_child(id, bit, Some(split));
+                    self.update(split);
+                    split
+                }
+                None => {
+                    let leaf = self.alloc(*cidr);
+                    self.set_child(id, bit, Some(leaf));
+                    leaf
+                }
+            };
+        }
+
+        for &id in path.iter().rev() {
+            self.update(id);
+        }
+        self.path = path;
+    }
+
+    /// Remove every address in `cidr`, splitting a present CIDR around it
+    /// into the halves that remain. Returns whether any address was in the
+    /// tree.
+    pub fn remove(&mut self, cidr: &Cidr) -> bool {
+        let mut path = std::mem::take(&mut self.path);
+        path.clear();
+
+        let mut id = ROOT;
+        loop {
+            path.push(id);
+            let node = self.node(id);
+            if node.cidr == *cidr {
+                if node.cidr_count == 0 {
+                    // Only the root can be empty
+                    self.path = path;
+                    return false;
+                }
+                self.detach(&mut path);
+                break;
+            }
+            if node.present() {
+                self.split(id, cidr, &mut path);
+                break;
+            }
+
+            let bit = cidr.bit(node.cidr.size());
+            let opt_child = if bit { node.right } else { node.left };
+            match opt_child.map(|c| (c, self.node(c).cidr)) {
+                Some((child, c)) if c.contains(cidr) => id = child,
+                Some((child, c)) if cidr.contains(&c) => {
+                    path.push(child);
+                    self.detach(&mut path);
+                    break;
+                }
+                // Nothing of `cidr` is in the tree
+                _ => {
+                    self.path = path;
+                    return false;
+                }
+            }
+        }
+
+        for &id in path.iter().rev() {
+            self.update(id);
+        }
+        self.path = path;
+        true
+    }
+
+    /// Drop the last node on `path` with everything below it.
+    fn detach(&mut self, path: &mut Vec<NodeId>) {
+        let id = path.pop().unwrap();
+        self.free_childs(id);
+        match path.last() {
+            Some(&parent) => {
+                self.free.push(id);
+                let bit = self.node(id).cidr.bit(self.node(parent).cidr.size());
+                self.set_child(parent, bit, None);
+                self.splice(path);
+            }
+            None => {
+                // The root stays, empty
+                let node = self.node_mut(ROOT);
+                node.value = None;
+                node.left = None;
+                node.right = None;
+                path.push(ROOT);
+            }
+        }
+    }
+
+    /// Turn the present node `id`, the last on `path`, into a chain of nodes
+    /// down to `cidr` with every half beside the chain keeping the value and
+    /// `cidr` itself left out.
+    fn split(&mut self, id: NodeId, cidr: &Cidr, path: &mut Vec<NodeId>) {
+        let value = self.node_mut(id).value.take().unwrap();
+        let mut id = id;
+        loop {
+            let [left, right] = self.node(id).cidr.halves();
+            let bit = cidr.bit(self.node(id).cidr.size());
+            let (inner, outer) = if bit { (right, left) } else { (left, right) };
+
+            let rest = self.alloc(outer);
+            self.node_mut(rest).value = Some(value.clone());
+            self.update(rest);
+            self.set_child(id, !bit, Some(rest));
+            if inner == *cidr {
+                break;
+            }
+
+            let next = self.alloc(inner);
+            self.set_child(id, bit, Some(next));
+            path.push(next);
+            id = next;
+        }
+        self.splice(path);
+    }
+
+    /// Nodes only exist where a CIDR is present or two subtrees branch, so
+    /// replace the last node on `path` by its only child if it has become
+    /// neither.
+    fn splice(&mut self, path: &mut Vec<NodeId>) {
+        let id = *path.last().unwrap();
+        let node = self.node(id);
+        if id == ROOT || node.present() {
+            return;
+        }
+        if let Some(child) = node.left.xor(node.right) {
+            path.pop();
+            let parent = *path.last().unwrap();
+            let bit = node.cidr.bit(self.node(parent).cidr.size());
+            self.set_child(parent, bit, Some(child));
+            self.free.push(id);
+        }
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.root().node_count
+    }
+
+    pub fn cidrs(&self) -> usize {
+        self.root().cidr_count
+    }
+
+    /// Exact number of covered addresses, saturating at `u128::MAX`.
+    pub fn addresses(&self) -> u128 {
+        self.root().addresses
+    }
+
+    /// The present CIDR containing all of `cidr` and its value, if any.
+    pub fn longest_match(&self, cidr: &Cidr) -> Option<(Cidr, &V)> {
+        let mut node = self.root();
+        loop {
+            if let Some(value) = &node.value {
+                return Some((node.cidr, value));
+            }
+            if node.cidr.size() >= cidr.size() {
+                return None;
+            }
+            let child = if cidr.bit(node.cidr.size()) {
+                node.right
+            } else {
+                node.left
+            };
+            node = child
+                .map(|c| self.node(c))
+                .filter(|t| t.cidr.contains(cidr))?;
+        }
+    }
+
+    /// The present CIDRs overlapping `cidr` in address order: the one
+    /// containing it, or all of those inside it.
+    pub fn overlapping(&self, cidr: &Cidr) -> Iter<'_, V> {
+        let mut id = ROOT;
+        let stack = loop {
+            let node = self.node(id);
+            if !node.cidr.contains(cidr) && !cidr.contains(&node.cidr) {
+                break vec![];
+            }
+            if node.present() || cidr.contains(&node.cidr) {
+                break vec![id];
+            }
+            let child = if cidr.bit(node.cidr.size()) {
+                node.right
+            } else {
+                node.left
+            };
+            match child {
+                Some(child) => id = child,
+                None => break vec![],
+            }
+        };
+        Iter { tree: self, stack }
+    }
+
+    /// Exact number of covered addresses inside `cidr`.
+    pub fn addresses_within(&self, cidr: &Cidr) -> u128 {
+        let mut node = self.root();
+        loop {
+            if node.present() {
+                return cidr.count();
+            }
+            if node.cidr.size() == cidr.size() {
+                return node.addresses;
+            }
+            let child = if cidr.bit(node.cidr.size()) {
+                node.right
+            } else {
+                node.left
+            };
+            node = match child.map(|c| self.node(c)) {
+                Some(t) if cidr.contains(&t.cidr) => return t.addresses,
+                Some(t) if t.cidr.contains(cidr) => t,
+                _ => return 0,
+            };
+        }
+    }
+
+    pub fn coverage(&self) -> f64 {
+        self.root().coverage
+    }
+
+    pub fn best_coverage(&self) -> Option<&(f64, usize, Cidr)> {
+        self.root().best_coverage.as_ref()
+    }
+
+    /// The present CIDRs and their values in address order.
+    pub fn iter(&self) -> Iter<'_, V> {
+        Iter {
+            tree: self,
+            stack: vec![ROOT],
+        }
+    }
+}
+
+/// Iterator over the present CIDRs of a `Tree` and their values.
+#[derive(Debug)]
+pub(crate) struct Iter<'a, V> {
+    tree: &'a Tree<V>,
+    stack: Vec<NodeId>,
+}
+
+impl<'a, V: Clone + PartialEq> Iterator for Iter<'a, V> {
+    type Item = (Cidr, &'a V);
+
+    fn next(&mut self) -> Option<(Cidr, &'a V)> {
+        while let Some(id) = self.stack.pop() {
+            let node = self.tree.node(id);
+            if let Some(value) = &node.value {
+                return Some((node.cidr, value));
+            }
+            // Push right first so the left child is visited first
+            self.stack.extend(node.right);
+            self.stack.extend(node.left);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tree;
+    use crate::cidr::{Cidr, Family};
+
+    #[test]
+    fn tree_insert() {
+        let cidrs = [
+            Cidr::parse("255.0.0.0/8").unwrap(),
+            Cidr::parse("255.100.0.0/16").unwrap(),
+            Cidr::parse("254.100.0.0/16").unwrap(),
+            Cidr::parse("13.14.15.16/32").unwrap(),
+        ];
+        let mut tree = Tree::new(Family::V4);
+
+        cidrs.iter().for_each(|c| tree.insert(c, ()));
+
+        assert_eq!(tree.cidrs(), 3);
+        // The root, 254.0.0.0/7 where the two networks branch and the three
+        // present CIDRs
+        assert_eq!(tree.nodes(), 5);
+        assert_eq!(
+            tree.coverage(),
+            1.0 / 256.0 + 1.0 / 65536.0 + 1.0 / 4294967296.0
+        );
+    }
+
+    #[test]
+    fn tree_insert_v6() {
+        let mut tree = Tree::new(Family::V6);
+        tree.insert(&Cidr::parse("2001:db8::/33").unwrap(), ());
+        tree.insert(&Cidr::parse("2001:db8:8000::/33").unwrap(), ());
+
+        assert_eq!(tree.cidrs(), 1);
+        assert_eq!(tree.coverage(), 1.0 / 4294967296.0);
+        assert_eq!(
+            tree.best_coverage().unwrap().2.to_pretty_string(),
+            "2001:db8::/31"
+        );
+    }
+
+    #[test]
+    fn tree_best_coverage_on_compressed_edge() {
+        let mut tree = Tree::new(Family::V4);
+        tree.insert(&Cidr::parse("10.0.0.1").unwrap(), ());
+        tree.insert(&Cidr::parse("10.0.0.6").unwrap(), ());
+        assert_eq!(tree.nodes(), 4);
+
+        // Same choice as a trie with a node per bit: widening either host to
+        // its /31 adds a single address, and ties go to the later candidate
+        let best = tree.best_coverage().unwrap();
+        assert_eq!(best.0, 0.5);
+        assert_eq!(best.1, 1);
+        assert_eq!(best.2.to_pretty_string(), "10.0.0.6/31");
+
+        let cidr = best.2;
+        tree.insert(&cidr, ());
+        assert_eq!(tree.cidrs(), 2);
+        assert_eq!(tree.coverage(), 3.0 / 4294967296.0);
+    }
+
+    #[test]
+    fn tree_reuses_nodes() {
+        let mut tree = Tree::new(Family::V4);
+        (0..16).for_each(|i| tree.insert(&Cidr::parse(&format!("10.0.0.{}", i * 2)).unwrap(), ()));
+        assert_eq!(tree.nodes(), 32);
+        let allocated = tree.nodes.len();
+
+        // The /24 frees every node below it, which the next inserts reuse
+        tree.insert(&Cidr::parse("10.0.0.0/24").unwrap(), ());
+        assert_eq!(tree.nodes(), 2);
+        (0..16).for_each(|i| tree.insert(&Cidr::parse(&format!("10.0.1.{}", i * 2)).unwrap(), ()));
+        assert_eq!(tree.nodes(), 34);
+        assert_eq!(tree.nodes.len(), allocated + 2);
+
+        tree.clear();
+        assert_eq!((tree.nodes(), tree.cidrs(), tree.coverage()), (1, 0, 0.0));
+        assert!(tree.nodes.capacity() >= allocated + 2);
+        assert_eq!(tree.iter().count(), 0);
+    }
+
+    #[test]
+    fn tree_addresses_within() {
+        let mut tree = Tree::new(Family::V4);
+        for c in &["10.0.0.1", "10.0.0.6", "10.0.1.0/24"] {
+            tree.insert(&Cidr::parse(c).unwrap(), ());
+        }
+        let within = |c: &str| tree.addresses_within(&Cidr::parse(c).unwrap());
+
+        assert_eq!(tree.addresses(), 258);
+        assert_eq!(within("0.0.0.0/0"), 258);
+        assert_eq!(within("10.0.0.0/23"), 258);
+        assert_eq!(within("10.0.0.0/24"), 2);
+        // Inside a compressed edge, and on either side of it
+        assert_eq!(within("10.0.0.4/30"), 1);
+        assert_eq!(within("10.0.0.2/31"), 0);
+        assert_eq!(within("10.0.2.0/24"), 0);
+        // Inside a present CIDR
+        assert_eq!(within("10.0.1.128/25"), 128);
+    }
+
+    #[test]
+    fn tree_excluded() {
+        let mut tree = Tree::new(Family::V4);
+        tree.insert(&Cidr::parse("10.0.0.1").unwrap(), ());
+        tree.insert(&Cidr::parse("10.0.0.6").unwrap(), ());
+        tree.set_excluded(vec![Cidr::parse("10.0.0.7").unwrap()]);
+
+        // 10.0.0.6/31 and everything above it overlap the exclusion
+        assert!(tree.is_excluded(&Cidr::parse("10.0.0.0/29").unwrap()));
+        assert!(!tree.is_excluded(&Cidr::parse("10.0.0.0/30").unwrap()));
+        let best = tree.best_coverage().unwrap();
+        assert_eq!(best.2.to_pretty_string(), "10.0.0.0/31");
+
+        tree.insert(&Cidr::parse("10.0.0.0/30").unwrap(), ());
+        assert_eq!(tree.best_coverage(), None);
+
+        // An empty tree has no candidates either
+        let mut empty: Tree = Tree::new(Family::V6);
+        empty.set_excluded(vec![]);
+        assert_eq!(empty.best_coverage(), None);
+    }
+
+    #[test]
+    fn tree_remove_keeps_aggregates() {
+        // Insert and remove pseudo-random CIDRs, checking every aggregate
+        // against a tree built from scratch
+        let mut state = 7u64;
+        let mut random = |range: u64| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (state >> 33) % range
+        };
+
+        let mut tree = Tree::new(Family::V4);
+        for step in 0..400 {
+            let size = 20 + random(13) as usize;
+            let value = 0x0a00_0000 | (random(1 << 14) as u128) << 2;
+            let cidr = Cidr::from_value(Family::V4, value, size);
+            if step % 3 == 0 {
+                let before = tree.addresses();
+                let removed = tree.remove(&cidr);
+                assert_eq!(removed, before != tree.addresses());
+                assert_eq!(tree.addresses_within(&cidr), 0);
+            } else {
+                tree.insert(&cidr, ());
+            }
+
+            let mut fresh = Tree::new(Family::V4);
+            tree.iter().for_each(|(c, _)| fresh.insert(&c, ()));
+            assert_eq!(tree.nodes(), fresh.nodes());
+            assert_eq!(tree.cidrs(), fresh.cidrs());
+            assert_eq!(tree.addresses(), fresh.addresses());
+            assert_eq!(tree.coverage(), fresh.coverage());
+            assert_eq!(tree.best_coverage(), fresh.best_coverage());
+        }
+
+        assert!(tree.remove(&Cidr::root(Family::V4)));
+        assert!(!tree.remove(&Cidr::root(Family::V4)));
+        assert_eq!((tree.nodes(), tree.cidrs(), tree.coverage()), (1, 0, 0.0));
+    }
+}